@@ -0,0 +1,603 @@
+//! Rebuilds the intermediate images of an image from its `docker save` archive, for
+//! images whose history has no layer ids (anything pulled rather than built locally).
+//!
+//! The config history lists every build step, and the steps that add files line up in
+//! order with the filesystem layers. An intermediate image is the first N layers with the
+//! image's own config, its history cut short after the Nth layer. Steps that only change
+//! metadata (ENV, LABEL, CMD, ...) have no layer and their config changes can't be told
+//! apart, so they are grouped with the next filesystem layer (trailing ones with the last).
+//! The final entrypoint, command and user usually need files or users from later layers,
+//! so intermediate images go without them (`--entrypoint` and `--user` set them instead).
+//! Steps the daemon still has an image id for are run as they are.
+//!
+//! The archive can come from the daemon or from a file: a `docker save` tarball or an OCI
+//! image layout, packed or unpacked.
+//!
+//! Images are only loaded into the daemon when a layer is first run. They are tagged
+//! `docker-bisect:<hash of the layers>`, so later runs (and the result cache) reuse them.
+use std::collections::HashSet;
+use std::env;
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use dockworker::{Docker, ImageLayer};
+use serde_json::{json, Value};
+
+use super::{cache, Layer};
+
+/// A build step from the config history.
+#[derive(Debug)]
+struct Step {
+    created_by: String,
+    empty_layer: bool,
+}
+
+/// An unpacked image archive that can rebuild the image at any of its layers.
+#[derive(Debug)]
+pub struct ImageArchive {
+    dir: PathBuf,
+    /// The daemon's image id for each step, bottom-up, used instead of rebuilding the
+    /// image. Only the top one is known unless the daemon built the image itself.
+    ids: Vec<Option<String>>,
+    config_path: String,
+    config: Value,
+    layer_paths: Vec<String>,
+    steps: Vec<Step>,
+    /// Where images are written before they are loaded: `dir` unless the archive was
+    /// opened in place, as that directory may well be read-only.
+    scratch: PathBuf,
+    remove_dir: bool,
+    loaded: Mutex<HashSet<String>>,
+}
+
+impl ImageArchive {
+    /// Saves `image` from the daemon (`docker save`) and unpacks it into `dir`.
+    /// The directory is removed again when the archive is dropped.
+    pub fn export(docker: &Docker, image: &str, dir: &Path) -> Result<ImageArchive, Error> {
+        let saved = docker
+            .export_image(image)
+            .map_err(|e| Error::other(format!("can't save image {}: {}", image, e)))?;
+        // Ids rather than the name, as a tag can move between runs of the cache.
+        let ids = docker
+            .history_image(image)
+            .map(|history| history.into_iter().rev().map(|event| event.id).collect())
+            .unwrap_or_default();
+        ImageArchive::unpack(saved, dir, ids)
+    }
+
+    /// Opens a `docker save` tarball or an OCI image layout, either as a tarball or as a
+    /// directory. Tarballs are unpacked into `dir`, which is removed again when the archive
+    /// is dropped. Directories are read in place, and `dir` is only used to build images
+    /// in. Every layer, including the top one, is loaded into the daemon when run.
+    pub fn open(path: &Path, dir: &Path) -> Result<ImageArchive, Error> {
+        if path.is_dir() {
+            let mut archive = ImageArchive::read(path, Vec::new())?;
+            archive.scratch = dir.to_path_buf();
+            return Ok(archive);
+        }
+        let file = File::open(path)
+            .map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+        ImageArchive::unpack(file, dir, Vec::new())
+    }
+
+    fn unpack<R: Read>(
+        tarball: R,
+        dir: &Path,
+        ids: Vec<Option<String>>,
+    ) -> Result<ImageArchive, Error> {
+        fs::create_dir_all(dir)?;
+        let archive = tar::Archive::new(tarball)
+            .unpack(dir)
+            .and_then(|_| ImageArchive::read(dir, ids));
+        match archive {
+            Ok(mut archive) => {
+                archive.remove_dir = true;
+                Ok(archive)
+            }
+            Err(e) => {
+                let _ = fs::remove_dir_all(dir);
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads an unpacked archive: the manifest, the image config it points to and the
+    /// layer tarballs. `docker save`'s `manifest.json` is used if there is one, as newer
+    /// dockers write an OCI layout alongside it. `ids` are the daemon's image ids for the
+    /// history steps, bottom-up, if it has them.
+    fn read(dir: &Path, mut ids: Vec<Option<String>>) -> Result<ImageArchive, Error> {
+        let (config_path, layer_paths) = if dir.join("manifest.json").exists() {
+            docker_manifest(dir)?
+        } else if dir.join("index.json").exists() {
+            oci_manifest(dir)?
+        } else {
+            return Err(invalid(&format!(
+                "{} has neither a manifest.json nor an index.json",
+                dir.display()
+            )));
+        };
+
+        let mut config = read_json(&dir.join(&config_path))?;
+        let mut steps: Vec<Step> = config["history"]
+            .as_array()
+            .map(|history| {
+                history
+                    .iter()
+                    .map(|step| Step {
+                        created_by: match step["created_by"].as_str() {
+                            Some(command) if !command.trim().is_empty() => command.to_string(),
+                            _ => "(no command)".to_string(),
+                        },
+                        empty_layer: step["empty_layer"].as_bool().unwrap_or(false),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        if steps.iter().filter(|s| !s.empty_layer).count() != layer_paths.len() {
+            // No usable history: one anonymous step per layer.
+            if let Some(config) = config.as_object_mut() {
+                config.remove("history");
+            }
+            steps = layer_paths
+                .iter()
+                .map(|path| Step {
+                    created_by: format!("(no history) {}", path),
+                    empty_layer: false,
+                })
+                .collect();
+        }
+        if ids.len() != steps.len() {
+            // Only the top image can still be matched up.
+            let top = ids.pop().flatten();
+            ids = vec![None; steps.len()];
+            if let Some(last) = ids.last_mut() {
+                *last = top;
+            }
+        }
+
+        Ok(ImageArchive {
+            dir: dir.to_path_buf(),
+            ids,
+            config_path,
+            config,
+            layer_paths,
+            steps,
+            scratch: dir.to_path_buf(),
+            remove_dir: false,
+            loaded: Mutex::default(),
+        })
+    }
+
+    /// One layer per filesystem layer, bottom-up. Heights are history indexes (the same as
+    /// with `docker history`). The creation command is the layer's own step, followed by a
+    /// line for each metadata step grouped into it.
+    pub fn layers(&self) -> Vec<Layer> {
+        let mut layers: Vec<Layer> = Vec::new();
+        let mut grouped: Vec<&str> = Vec::new();
+        let mut count = 0;
+        for (height, step) in self.steps.iter().enumerate() {
+            if step.empty_layer {
+                grouped.push(&step.created_by);
+            } else {
+                count += 1;
+                grouped.insert(0, &step.created_by);
+                layers.push(Layer {
+                    height,
+                    image_name: self.image_name(height, count),
+                    creation_command: grouped.join("\n"),
+                });
+                grouped.clear();
+            }
+        }
+        if let Some(last) = layers.last_mut() {
+            for command in grouped {
+                last.creation_command.push('\n');
+                last.creation_command.push_str(command);
+            }
+        }
+        layers
+    }
+
+    /// The history as `docker history` would show it if every layer had been built
+    /// locally: newest first, each step with the id of the image that includes it.
+    pub fn histories(&self) -> Vec<ImageLayer> {
+        let layers = self.layers();
+        self.steps
+            .iter()
+            .enumerate()
+            .map(|(height, step)| ImageLayer {
+                id: layers
+                    .iter()
+                    .find(|l| l.height >= height)
+                    .or_else(|| layers.last())
+                    .map(|l| l.image_name.clone()),
+                created: 0,
+                created_by: step.created_by.clone(),
+                tags: None,
+                size: 0,
+                comment: String::new(),
+            })
+            .rev()
+            .collect()
+    }
+
+    /// The daemon's image for the step at `height` if it has one, else the name of the
+    /// image to build from the first `count` layers. The top layer takes the trailing
+    /// metadata steps, so it is the top image.
+    fn image_name(&self, height: usize, count: usize) -> String {
+        let id = if count == self.layer_paths.len() {
+            self.ids.last()
+        } else {
+            self.ids.get(height)
+        };
+        match id {
+            Some(Some(id)) => id.clone(),
+            _ => format!(
+                "docker-bisect:{}",
+                cache::fingerprint(&(&self.config_path, &self.layer_paths[..count]))
+            ),
+        }
+    }
+
+    /// How many filesystem layers make up the image for `layer`.
+    fn layer_count(&self, layer: &Layer) -> usize {
+        self.steps[..=layer.height]
+            .iter()
+            .filter(|s| !s.empty_layer)
+            .count()
+    }
+
+    /// The layer tarballs that make up the image for `layer`, bottom-up.
+    #[cfg(unix)]
+    pub(crate) fn layer_files(&self, layer: &Layer) -> Vec<PathBuf> {
+        self.layer_paths[..self.layer_count(layer)]
+            .iter()
+            .map(|path| self.dir.join(path))
+            .collect()
+    }
+
+    /// The directory to work in: the one passed to `open` or `export`.
+    #[cfg(unix)]
+    pub(crate) fn scratch(&self) -> &Path {
+        &self.scratch
+    }
+
+    /// The config (entrypoint, environment, working directory, ...) of the image for `layer`.
+    #[cfg(unix)]
+    pub(crate) fn config(&self, layer: &Layer) -> Value {
+        self.image_config(self.layer_count(layer))
+    }
+
+    /// The config of the image made of the first `count` layers: the image's own, its
+    /// history and layers cut short, and without the entrypoint, command and user unless
+    /// it is the whole image.
+    fn image_config(&self, count: usize) -> Value {
+        let mut config = self.config.clone();
+        let last_step = self
+            .steps
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| !s.empty_layer)
+            .nth(count - 1)
+            .map_or(self.steps.len(), |(i, _)| i);
+        if let Some(history) = config.get_mut("history").and_then(Value::as_array_mut) {
+            history.truncate(last_step + 1);
+        }
+        if let Some(diff_ids) = config
+            .pointer_mut("/rootfs/diff_ids")
+            .and_then(Value::as_array_mut)
+        {
+            diff_ids.truncate(count);
+        }
+        if count < self.layer_paths.len() {
+            if let Some(settings) = config.get_mut("config").and_then(Value::as_object_mut) {
+                for key in &["Entrypoint", "Cmd", "User"] {
+                    settings.remove(*key);
+                }
+            }
+        }
+        config
+    }
+
+    /// Makes sure the image for `layer` is in the daemon, building and loading it if need be.
+    /// Loads happen one at a time; the daemon would serialise them anyway.
+    pub(crate) fn load(&self, docker: &Docker, layer: &Layer) -> Result<(), Error> {
+        let mut loaded = self.loaded.lock().expect("loaded images lock");
+        let known = self.ids.iter().flatten().any(|id| *id == layer.image_name);
+        if known || loaded.contains(&layer.image_name) {
+            return Ok(());
+        }
+        if docker.history_image(&layer.image_name).is_err() {
+            let count = self.layer_count(layer);
+            let path = self
+                .scratch
+                .join(format!("{}.tar", cache::fingerprint(&layer.image_name)));
+            let written = fs::create_dir_all(&self.scratch)
+                .and_then(|_| self.write_image(count, &layer.image_name, &path));
+            let result = written.and_then(|_| {
+                docker
+                    .load_image(true, &path)
+                    .map_err(|e| Error::other(format!("can't load {}: {}", layer.image_name, e)))
+            });
+            let _ = fs::remove_file(&path);
+            result?;
+        }
+        loaded.insert(layer.image_name.clone());
+        Ok(())
+    }
+
+    /// Writes a `docker load`able archive of the image made of the first `count` layers.
+    fn write_image(&self, count: usize, tag: &str, path: &Path) -> Result<(), Error> {
+        if count == 0 || count > self.layer_paths.len() {
+            return Err(invalid("not that many layers"));
+        }
+        let config = self.image_config(count);
+        let manifest = json!([{
+            "Config": "config.json",
+            "RepoTags": [tag],
+            "Layers": &self.layer_paths[..count],
+        }]);
+
+        let mut tar = tar::Builder::new(File::create(path)?);
+        for (name, value) in &[("manifest.json", &manifest), ("config.json", &config)] {
+            let bytes = serde_json::to_vec(value)?;
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, &bytes[..])?;
+        }
+        for layer in &self.layer_paths[..count] {
+            tar.append_file(layer, &mut File::open(self.dir.join(layer))?)?;
+        }
+        tar.finish()
+    }
+}
+
+impl Drop for ImageArchive {
+    fn drop(&mut self) {
+        if self.remove_dir {
+            let _ = fs::remove_dir_all(&self.dir);
+        } else if self.scratch != self.dir {
+            // Only if nothing else was put there.
+            let _ = fs::remove_dir(&self.scratch);
+        }
+    }
+}
+
+/// The config and layer paths from a `docker save` `manifest.json`.
+fn docker_manifest(dir: &Path) -> Result<(String, Vec<String>), Error> {
+    let manifest = read_json(&dir.join("manifest.json"))?;
+    let manifest = manifest
+        .get(0)
+        .ok_or_else(|| invalid("manifest.json lists no images"))?;
+    let config_path = manifest["Config"]
+        .as_str()
+        .ok_or_else(|| invalid("manifest.json has no Config"))?
+        .to_string();
+    let layer_paths = manifest["Layers"]
+        .as_array()
+        .map(|layers| {
+            layers
+                .iter()
+                .filter_map(|l| l.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((config_path, layer_paths))
+}
+
+/// The config and layer paths of an OCI image layout, following `index.json` down to an
+/// image manifest. Where there is a choice the manifest for this platform is used.
+fn oci_manifest(dir: &Path) -> Result<(String, Vec<String>), Error> {
+    let mut manifest = read_json(&dir.join("index.json"))?;
+    while let Some(manifests) = manifest["manifests"].as_array() {
+        let chosen = manifests
+            .iter()
+            .find(|m| is_this_platform(&m["platform"]))
+            .or_else(|| manifests.first())
+            .ok_or_else(|| invalid("index.json lists no images"))?;
+        let path = blob_path(chosen)?;
+        manifest = read_json(&dir.join(path))?;
+    }
+    let config_path = blob_path(&manifest["config"])?;
+    let layer_paths = manifest["layers"]
+        .as_array()
+        .map(|layers| layers.iter().map(blob_path).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))?;
+    Ok((config_path, layer_paths))
+}
+
+/// Where the blob of an OCI descriptor lives in the layout.
+fn blob_path(descriptor: &Value) -> Result<String, Error> {
+    let digest = descriptor["digest"]
+        .as_str()
+        .ok_or_else(|| invalid("OCI descriptor has no digest"))?;
+    let mut parts = digest.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some(algorithm), Some(hex)) => Ok(format!("blobs/{}/{}", algorithm, hex)),
+        _ => Err(invalid(&format!("bad digest {}", digest))),
+    }
+}
+
+fn is_this_platform(platform: &Value) -> bool {
+    platform["os"] == "linux" && platform["architecture"] == architecture()
+}
+
+/// This machine's architecture as OCI (Go) names it.
+fn architecture() -> &'static str {
+    match env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        arch => arch,
+    }
+}
+
+fn read_json(path: &Path) -> Result<Value, Error> {
+    let file =
+        File::open(path).map_err(|e| Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::Read;
+
+    /// An unpacked `docker save` of FROM scratch / ADD / ENV / RUN / CMD.
+    fn unpacked(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("docker-bisect-{}-{}", name, std::process::id()));
+        for layer in &["a", "b"] {
+            fs::create_dir_all(dir.join(layer)).unwrap();
+            fs::write(dir.join(layer).join("layer.tar"), layer).unwrap();
+        }
+        let manifest = json!([{
+            "Config": "cafe.json",
+            "RepoTags": ["app:latest"],
+            "Layers": ["a/layer.tar", "b/layer.tar"],
+        }]);
+        let config = json!({
+            "config": {"Env": ["X=1"], "Cmd": ["sh"], "Entrypoint": ["/app"], "User": "app"},
+            "history": [
+                {"created_by": "ADD rootfs.tar /"},
+                {"created_by": "ENV X=1", "empty_layer": true},
+                {"created_by": "RUN apk add curl"},
+                {"created_by": "CMD [\"sh\"]", "empty_layer": true},
+                {"comment": "buildkit.dockerfile.v0", "empty_layer": true},
+            ],
+            "rootfs": {"type": "layers", "diff_ids": ["sha256:a", "sha256:b"]},
+        });
+        fs::write(dir.join("manifest.json"), manifest.to_string()).unwrap();
+        fs::write(dir.join("cafe.json"), config.to_string()).unwrap();
+        dir
+    }
+
+    #[test]
+    fn metadata_steps_join_the_next_layer() {
+        let dir = unpacked("layers");
+        let archive = ImageArchive::read(&dir, vec![Some("sha256:cafe".into())]).unwrap();
+        let layers = archive.layers();
+        let histories = archive.histories();
+        let mut ids = vec![None; 5];
+        ids[0] = Some("sha256:base".to_string());
+        let built_here = ImageArchive::read(&dir, ids).unwrap().layers();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            vec![
+                (0, "ADD rootfs.tar /".to_string()),
+                (
+                    2,
+                    "RUN apk add curl\nENV X=1\nCMD [\"sh\"]\n(no command)".to_string()
+                ),
+            ],
+            layers
+                .iter()
+                .map(|l| (l.height, l.creation_command.clone()))
+                .collect::<Vec<_>>()
+        );
+        assert!(layers[0].image_name.starts_with("docker-bisect:"));
+        assert_eq!("sha256:cafe", layers[1].image_name);
+        let ids: Vec<_> = histories.iter().map(|h| h.id.clone().unwrap()).collect();
+        assert_eq!(
+            vec![
+                &layers[1].image_name,
+                &layers[1].image_name,
+                &layers[1].image_name,
+                &layers[1].image_name,
+                &layers[0].image_name
+            ],
+            ids.iter().collect::<Vec<_>>()
+        );
+        assert_eq!("sha256:base", built_here[0].image_name);
+        assert!(built_here[1].image_name.starts_with("docker-bisect:"));
+    }
+
+    #[test]
+    fn oci_layout_follows_index_to_this_platform() {
+        let dir = env::temp_dir().join(format!("docker-bisect-oci-{}", std::process::id()));
+        let blobs = dir.join("blobs").join("sha256");
+        fs::create_dir_all(&blobs).unwrap();
+        let blob = |name: &str, value: Value| {
+            fs::write(blobs.join(name), value.to_string()).unwrap();
+            json!({ "digest": format!("sha256:{}", name) })
+        };
+        let config = blob(
+            "c0",
+            json!({
+                "history": [{"created_by": "ADD rootfs.tar /"}, {"created_by": "RUN make"}],
+                "rootfs": {"type": "layers", "diff_ids": ["sha256:a", "sha256:b"]},
+            }),
+        );
+        let manifest = blob(
+            "m0",
+            json!({ "config": config, "layers": [{"digest": "sha256:l0"}, {"digest": "sha256:l1"}] }),
+        );
+        let mut this_platform = manifest.clone();
+        this_platform["platform"] = json!({"os": "linux", "architecture": architecture()});
+        let other = blob("m1", json!({ "config": {"digest": "sha256:missing"} }));
+        let index = blob("i0", json!({ "manifests": [other, this_platform] }));
+        fs::write(
+            dir.join("index.json"),
+            json!({ "manifests": [index] }).to_string(),
+        )
+        .unwrap();
+
+        let archive = ImageArchive::open(&dir, &dir.join("unused"));
+        let _ = fs::remove_dir_all(&dir);
+        let archive = archive.unwrap();
+
+        assert_eq!("blobs/sha256/c0", archive.config_path);
+        assert_eq!(dir.join("unused"), archive.scratch);
+        assert_eq!(
+            vec!["blobs/sha256/l0", "blobs/sha256/l1"],
+            archive.layer_paths
+        );
+        assert_eq!(
+            vec![0, 1],
+            archive
+                .layers()
+                .iter()
+                .map(|l| l.height)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn intermediate_image_has_fewer_layers() {
+        let dir = unpacked("write");
+        let archive = ImageArchive::read(&dir, Vec::new()).unwrap();
+        let path = dir.join("out.tar");
+        archive.write_image(1, "docker-bisect:test", &path).unwrap();
+
+        let mut files = Vec::new();
+        let mut config = String::new();
+        for entry in tar::Archive::new(File::open(&path).unwrap())
+            .entries()
+            .unwrap()
+        {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().to_string();
+            if name == "config.json" {
+                entry.read_to_string(&mut config).unwrap();
+            }
+            files.push(name);
+        }
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(vec!["manifest.json", "config.json", "a/layer.tar"], files);
+        let config: Value = serde_json::from_str(&config).unwrap();
+        assert_eq!(1, config["history"].as_array().unwrap().len());
+        assert_eq!(json!(["sha256:a"]), config["rootfs"]["diff_ids"]);
+        assert_eq!(json!({"Env": ["X=1"]}), config["config"]);
+        assert_eq!(json!("app"), archive.image_config(2)["config"]["User"]);
+    }
+}
@@ -1,43 +1,283 @@
+#[macro_use]
 extern crate clap;
 extern crate colored;
+extern crate ctrlc;
 extern crate docker_bisect;
 extern crate dockworker;
+extern crate regex;
 extern crate terminal_size;
 
-use std::io::Error;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind};
 use colored::*;
 use docker_bisect::*;
 use dockworker::*;
+use regex::Regex;
 use terminal_size::{terminal_size, Width};
 
 fn main() {
+    let formats = formats();
+    let runtimes = runtimes();
     let matches = App::new("docker-bisect")
         .version("0.1")
         .about("Run a command against image layers, find which layers change the output.")
+        .setting(AppSettings::TrailingVarArg)
+        .usage("docker-bisect [FLAGS] [OPTIONS] <image_name> [--] <command>...")
         .arg(
             Arg::with_name("timeout")
                 .short("t")
                 .long("timeout")
-                .help("Number of seconds to run each command for"),
+                .value_name("duration")
+                .help("How long each command may run, e.g. 90s or 5m (default is 10s)")
+                .takes_value(true)
+                .validator(is_timeout),
+        ).arg(
+            Arg::with_name("kill-signal")
+                .long("kill-signal")
+                .value_name("signal")
+                .help("Signal sent to a command that runs past the timeout (default is SIGTERM)")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("kill-grace")
+                .long("kill-grace")
+                .value_name("duration")
+                .help("Time between the kill signal and SIGKILL (default is 10s)")
+                .takes_value(true)
+                .validator(is_duration),
+        ).arg(
+            Arg::with_name("env")
+                .short("e")
+                .long("env")
+                .value_name("var")
+                .help("Environment variable KEY=value for the command, or KEY to pass on the host's (repeatable)")
+                .number_of_values(1)
+                .multiple(true),
+        ).arg(
+            Arg::with_name("workdir")
+                .short("w")
+                .long("workdir")
+                .value_name("dir")
+                .help("Working directory for the command (default is the image's)")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("user")
+                .short("u")
+                .long("user")
+                .value_name("user")
+                .help("User to run the command as: name or uid, optionally with :group (default is the image's, none for rebuilt layers)")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("entrypoint")
+                .long("entrypoint")
+                .value_name("program")
+                .help("Run the command with this instead of the image's entrypoint (\"\" for none; rebuilt layers have none)")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("mount")
+                .long("mount")
+                .value_name("src:dst[:ro]")
+                .help("Bind-mount a host file or directory into each container (repeatable, no caching)")
+                .validator(parses::<Mount>)
+                .number_of_values(1)
+                .multiple(true),
+        ).arg(
+            Arg::with_name("copy")
+                .long("copy")
+                .value_name("src:dst")
+                .help("Copy a host file or directory into each container before it starts (repeatable, no caching)")
+                .validator(parses::<FileCopy>)
+                .number_of_values(1)
+                .multiple(true),
+        ).arg(
+            Arg::with_name("network")
+                .long("network")
+                .help("Network for each container (default is the runtime's, bridge for docker)")
+                .takes_value(true)
+                .possible_values(&["none", "bridge", "host"]),
+        ).arg(
+            Arg::with_name("memory")
+                .long("memory")
+                .value_name("size")
+                .help("Memory limit for each container, e.g. 512m or 2g")
+                .takes_value(true)
+                .validator(is_memory),
+        ).arg(
+            Arg::with_name("cpus")
+                .long("cpus")
+                .value_name("N")
+                .help("CPUs each container may use, e.g. 1.5")
+                .takes_value(true)
+                .validator(is_cpus),
+        ).arg(
+            Arg::with_name("pids-limit")
+                .long("pids-limit")
+                .value_name("N")
+                .help("Maximum number of processes in each container (at most 32767: the dockworker client passes it on as an i16)")
+                .takes_value(true)
+                .validator(is_pids_limit),
         ).arg(
             Arg::with_name("image")
                 .value_name("image_name")
                 .help("Docker image name or id to use")
                 .required(true)
                 .takes_value(true),
+        ).arg(
+            Arg::with_name("archive")
+                .long("archive")
+                .help("image_name is a docker save tarball or OCI layout (file or directory)"),
         ).arg(
             Arg::with_name("command")
-                .help("Command and args to call in the container")
+                .help("Command and args to call in the container (everything after the image, or after --)")
                 .required(true)
                 .multiple(true),
+        ).arg(
+            Arg::with_name("runtime")
+                .long("runtime")
+                .help("What runs the containers (default is docker; oci and chroot need --archive)")
+                .takes_value(true)
+                .possible_values(&runtimes),
+        ).arg(
+            Arg::with_name("host")
+                .short("H")
+                .long("host")
+                .value_name("host")
+                .help("Docker API to use: unix socket path or tcp:// address (default is $DOCKER_HOST, the docker socket or the rootless Podman socket)")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("oci-runtime")
+                .long("oci-runtime")
+                .value_name("program")
+                .help("OCI runtime used by --runtime oci (default is runc)")
+                .takes_value(true),
         ).arg(
             Arg::with_name("truncate")
                 .long("truncate")
-                .help("Max width of printed layer commands (default is term width)"),
+                .value_name("width")
+                .help("Max width of printed layer commands (default is term width)")
+                .takes_value(true)
+                .validator(is_number),
+        ).arg(
+            Arg::with_name("compare")
+                .long("compare")
+                .help("What decides if two layers differ (default is output)")
+                .takes_value(true)
+                .possible_values(&["output", "exit-code", "both"]),
+        ).arg(
+            Arg::with_name("streams")
+                .long("streams")
+                .help("Which output streams are compared (default is both)")
+                .takes_value(true)
+                .possible_values(&["stdout", "stderr", "both"]),
+        ).arg(
+            Arg::with_name("strip-ansi")
+                .long("strip-ansi")
+                .help("Remove ANSI colour codes before comparing output"),
+        ).arg(
+            Arg::with_name("replace")
+                .long("replace")
+                .value_names(&["regex", "replacement"])
+                .help("Replace matches of regex before comparing output (repeatable)")
+                .number_of_values(2)
+                .multiple(true),
+        ).arg(
+            Arg::with_name("ignore-regex")
+                .long("ignore-regex")
+                .value_name("regex")
+                .help("Ignore output lines matching regex (repeatable)")
+                .validator(is_regex)
+                .number_of_values(1)
+                .multiple(true),
+        ).arg(
+            Arg::with_name("trim")
+                .long("trim")
+                .help("Ignore trailing whitespace in output"),
+        ).arg(
+            Arg::with_name("sort-lines")
+                .long("sort-lines")
+                .help("Ignore the order of output lines"),
+        ).arg(
+            Arg::with_name("run")
+                .long("run")
+                .value_name("script")
+                .help("Host shell command that reads a layer's output and prints good, bad or skip")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("first-bad")
+                .long("first-bad")
+                .help("Assume a single regression and only look for the first bad layer"),
+        ).arg(
+            Arg::with_name("exhaustive")
+                .long("exhaustive")
+                .help("Run the command on every layer (finds changes that are later undone)")
+                .conflicts_with("first-bad"),
+        ).arg(
+            Arg::with_name("good")
+                .long("good")
+                .value_name("layer")
+                .help("Known good layer (height or id) to start from instead of the first layer")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("bad")
+                .long("bad")
+                .value_name("layer")
+                .help("Known bad layer (height or id) to end at instead of the last layer")
+                .takes_value(true),
+        ).arg(
+            Arg::with_name("jobs")
+                .short("j")
+                .long("jobs")
+                .value_name("N")
+                .help("Number of containers to run at once (default is 4)")
+                .takes_value(true)
+                .validator(is_positive),
+        ).arg(
+            Arg::with_name("no-cache")
+                .long("no-cache")
+                .help("Run every layer again instead of reusing cached results"),
+        ).arg(
+            Arg::with_name("keep-containers")
+                .long("keep-containers")
+                .help("Leave the containers behind instead of removing them (for debugging)"),
+        ).arg(
+            Arg::with_name("repeat")
+                .long("repeat")
+                .value_name("N")
+                .help("Run each layer N times and skip layers whose runs disagree (no caching)")
+                .takes_value(true)
+                .validator(is_positive),
+        ).arg(
+            Arg::with_name("diff-context")
+                .long("diff-context")
+                .value_name("N")
+                .help("Unchanged lines shown around each change in the output diff (default is 3)")
+                .takes_value(true)
+                .validator(is_number),
+        ).arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("How to report the results (default is text)")
+                .takes_value(true)
+                .possible_values(&formats),
+        ).arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("file")
+                .help("Write the report to a file instead of stdout (not for text)")
+                .takes_value(true),
         ).get_matches();
 
+    // Text goes to the terminal only; checked now rather than after the whole bisect.
+    if matches.is_present("output") && matches.value_of("format").unwrap_or("text") == "text" {
+        fail("--output needs a --format other than text");
+    }
+
     let image_name = matches.value_of("image").expect("image expected");
     let mut command_line = Vec::<String>::new();
 
@@ -45,75 +285,392 @@ fn main() {
         command_line.push(arg.to_string());
     }
 
-    let mut trunc_size: usize = matches
-        .value_of("truncate")
-        .unwrap_or("100")
-        .parse()
-        .expect("Can't parse truncate value, expected --truncate=10 ");
+    let trunc_size = match terminal_size() {
+        Some((Width(w), _)) => number(&matches, "truncate", (w as usize).saturating_sub(10)),
+        None => number(&matches, "truncate", 100),
+    };
+
+    // Like `docker run -e`, a name on its own passes on the host's value, if it has one.
+    let env_vars: Vec<String> = matches
+        .values_of("env")
+        .map(|vars| {
+            vars.filter_map(|var| {
+                if var.contains('=') {
+                    Some(var.to_string())
+                } else {
+                    env::var(var).ok().map(|value| format!("{}={}", var, value))
+                }
+            })
+            .collect()
+        })
+        .unwrap_or_default();
+
+    // Docker takes a relative bind source for a volume name.
+    let mounts: Vec<Mount> = matches
+        .values_of("mount")
+        .map(|values| {
+            values
+                .map(|value| {
+                    let mut mount: Mount = value.parse().expect("validated by parses");
+                    mount.source = host_path(&mount.source);
+                    mount
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let copies: Vec<FileCopy> = matches
+        .values_of("copy")
+        .map(|values| {
+            values
+                .map(|value| {
+                    let mut copy: FileCopy = value.parse().expect("validated by parses");
+                    copy.source = host_path(&copy.source);
+                    copy
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    let limits = Limits {
+        network: matches
+            .value_of("network")
+            .and_then(|network| network.parse().ok())
+            .unwrap_or_default(),
+        memory: matches
+            .value_of("memory")
+            .and_then(|memory| parse_memory(memory).ok()),
+        cpus: value_t!(matches, "cpus", f64).ok(),
+        pids_limit: value_t!(matches, "pids-limit", u32).ok(),
+    };
 
-    let size = terminal_size();
-    if let Some((Width(w), _)) = size {
-        if trunc_size == 100 {
-            trunc_size = (w as usize) - 10;
+    let compare = match matches.value_of("compare").unwrap_or("output") {
+        "exit-code" => CompareMode::ExitCode,
+        "both" => CompareMode::Both,
+        _ => CompareMode::Output,
+    };
+    let streams = match matches.value_of("streams").unwrap_or("both") {
+        "stdout" => Streams::Stdout,
+        "stderr" => Streams::Stderr,
+        _ => Streams::Both,
+    };
+
+    let mut normalise = Normaliser::default();
+    if matches.is_present("strip-ansi") {
+        normalise.rules.push(Rule::strip_ansi());
+    }
+    if let Some(mut values) = matches.values_of("replace") {
+        while let (Some(regex), Some(replacement)) = (values.next(), values.next()) {
+            let regex = Regex::new(regex).unwrap_or_else(|e| {
+                clap::Error::with_description(
+                    &format!("Invalid regex for '--replace <regex>': {}", e),
+                    ErrorKind::InvalidValue,
+                )
+                .exit()
+            });
+            normalise.rules.push(Rule::Replace(regex, replacement.to_string()));
         }
     }
+    if let Some(values) = matches.values_of("ignore-regex") {
+        for regex in values {
+            let regex = Regex::new(regex).expect("validated by is_regex");
+            normalise.rules.push(Rule::DropLines(regex));
+        }
+    }
+    if matches.is_present("trim") {
+        normalise.rules.push(Rule::TrimTrailingWhitespace);
+    }
+    if matches.is_present("sort-lines") {
+        normalise.rules.push(Rule::SortLines);
+    }
 
-    let docker: Docker =
-        Docker::connect_with_defaults().expect("Can't connect to docker daemon. Is it running?");
+    let classifier: Option<Arc<dyn Classifier>> = matches.value_of("run").map(|script| {
+        Arc::new(ScriptClassifier {
+            script: script.to_string(),
+        }) as Arc<dyn Classifier>
+    });
 
-    let histories: Vec<ImageLayer> = docker
-        .history_image(image_name)
-        .expect("Can't get layers from image.");
+    let runtime = match matches.value_of("runtime").unwrap_or("docker") {
+        #[cfg(unix)]
+        "oci" => Runtime::Oci(matches.value_of("oci-runtime").unwrap_or("runc").to_string()),
+        #[cfg(unix)]
+        "chroot" => Runtime::Chroot,
+        _ => Runtime::Docker,
+    };
+
+    let dir = env::temp_dir().join(format!("docker-bisect-{}", std::process::id()));
+    let interrupted_dir = dir.clone();
+    let handler = ctrlc::set_handler(move || {
+        eprintln!("Interrupted, cleaning up containers...");
+        clean_up_containers();
+        let _ = fs::remove_dir_all(&interrupted_dir);
+        std::process::exit(130);
+    });
+    if let Err(e) = handler {
+        eprintln!("Can't clean up on Ctrl-C: {}", e);
+    }
+
+    let mut archive = None;
+    let mut histories: Vec<ImageLayer> = Vec::new();
+    if matches.is_present("archive") {
+        let opened = ImageArchive::open(Path::new(image_name), &dir)
+            .unwrap_or_else(|e| fail(format!("can't read the image archive: {}", e)));
+        histories = opened.histories();
+        archive = Some(Arc::new(opened));
+    }
 
-    let results: Result<Vec<Transition>, Error> = try_bisect(
+    // The other runtimes work from the archive alone.
+    if runtime == Runtime::Docker && archive.is_none() {
+        let docker: Docker = connect(matches.value_of("host")).unwrap_or_else(|e| fail(e));
+        histories = docker
+            .history_image(image_name)
+            .unwrap_or_else(|e| fail(format!("can't get the layers of {}: {}", image_name, e)));
+
+        // Pulled images have no ids for their layers, so rebuild them from `docker save`.
+        if histories.iter().any(|event| event.id.is_none()) {
+            eprintln!("Some layers have no id, saving the image to rebuild them...");
+            eprintln!("Rebuilt layers have no entrypoint, command or user: set them with --entrypoint and --user.");
+            let saved = ImageArchive::export(&docker, image_name, &dir)
+                .unwrap_or_else(|e| fail(format!("can't rebuild the layers: {}", e)));
+            histories = saved.histories();
+            archive = Some(Arc::new(saved));
+        }
+    }
+
+    let results: Result<Bisection, BisectError> = try_bisect(
         &histories,
-        command_line,
+        command_line.clone(),
         BisectOptions {
-            timeout_in_seconds: matches
-                .value_of("timeout")
-                .unwrap_or("10")
-                .parse()
-                .expect("Can't parse timeout value, expected --timeout=10 "),
+            timeout: duration(&matches, "timeout", Duration::from_secs(10)),
+            kill_signal: matches
+                .value_of("kill-signal")
+                .unwrap_or("SIGTERM")
+                .to_string(),
+            kill_grace: duration(&matches, "kill-grace", Duration::from_secs(10)),
+            env: env_vars,
+            workdir: matches.value_of("workdir").map(String::from),
+            user: matches.value_of("user").map(String::from),
+            entrypoint: matches.value_of("entrypoint").map(String::from),
+            mounts,
+            copies,
+            limits: limits.clone(),
             trunc_size,
+            compare,
+            streams,
+            normalise,
+            classifier,
+            mode: if matches.is_present("first-bad") {
+                BisectMode::FirstBad
+            } else if matches.is_present("exhaustive") {
+                BisectMode::Exhaustive
+            } else {
+                BisectMode::AllChanges
+            },
+            good: matches.value_of("good").map(String::from),
+            bad: matches.value_of("bad").map(String::from),
+            jobs: number(&matches, "jobs", 4),
+            cache_dir: if matches.is_present("no-cache") {
+                None
+            } else {
+                default_cache_dir()
+            },
+            repeat: number(&matches, "repeat", 1),
+            archive,
+            runtime,
+            host: matches.value_of("host").map(String::from),
+            keep_containers: matches.is_present("keep-containers"),
         },
     );
 
+    let mut bisection = results.unwrap_or_else(|e| fail(e));
+    bisection.transitions.sort_by_key(|t| t.after.layer.height);
+
+    let format = matches.value_of("format").unwrap_or("text");
+    if format == "text" {
+        let diff_context = number(&matches, "diff-context", 3);
+        print_text(&histories, bisection, trunc_size, diff_context);
+        return;
+    }
+
+    let report = Report::new(
+        image_name,
+        &command_line,
+        &histories,
+        bisection,
+        &limits,
+    );
+    let rendered = render(format, &report);
+    match matches.value_of("output") {
+        Some(path) => fs::write(path, rendered)
+            .unwrap_or_else(|e| fail(format!("can't write the report to {}: {}", path, e))),
+        None => println!("{}", rendered),
+    }
+}
+
+/// Prints the error and exits, for failures after the arguments were accepted.
+fn fail<E: std::fmt::Display>(error: E) -> ! {
+    eprintln!("{} {}", "error:".red().bold(), error);
+    std::process::exit(1);
+}
+
+fn is_duration(value: String) -> Result<(), String> {
+    parse_duration(&value).map(|_| ())
+}
+
+fn is_timeout(value: String) -> Result<(), String> {
+    match parse_duration(&value) {
+        Ok(timeout) if timeout == Duration::from_secs(0) => {
+            Err(format!("{} is not a duration above 0", value))
+        }
+        parsed => parsed.map(|_| ()),
+    }
+}
+
+fn is_number(value: String) -> Result<(), String> {
+    value
+        .parse::<usize>()
+        .map(|_| ())
+        .map_err(|_| format!("{} is not a number", value))
+}
+
+fn is_positive(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err(format!("{} is not a number above 0", value)),
+    }
+}
+
+fn is_pids_limit(value: String) -> Result<(), String> {
+    match value.parse::<u32>() {
+        Ok(n) if n > 0 && n <= MAX_PIDS_LIMIT => Ok(()),
+        _ => Err(format!("{} is not a number from 1 to {}", value, MAX_PIDS_LIMIT)),
+    }
+}
+
+fn is_memory(value: String) -> Result<(), String> {
+    parse_memory(&value).map(|_| ())
+}
+
+fn is_cpus(value: String) -> Result<(), String> {
+    match value.parse::<f64>() {
+        Ok(cpus) if cpus.is_finite() && cpus > 0.0 => Ok(()),
+        _ => Err(format!("{} is not a number of CPUs above 0", value)),
+    }
+}
+
+fn is_regex(value: String) -> Result<(), String> {
+    Regex::new(&value).map(|_| ()).map_err(|e| e.to_string())
+}
+
+fn parses<T: FromStr<Err = String>>(value: String) -> Result<(), String> {
+    value.parse::<T>().map(|_| ())
+}
+
+/// The absolute path of a file or directory on the host, which has to exist.
+fn host_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|e| fail(format!("{}: {}", path.display(), e)))
+}
+
+/// The value of a validated numeric option, or `default` if it wasn't given.
+fn number(matches: &ArgMatches, name: &str, default: usize) -> usize {
+    value_t!(matches, name, usize).unwrap_or(default)
+}
+
+/// The value of a validated duration option, or `default` if it wasn't given.
+fn duration(matches: &ArgMatches, name: &str, default: Duration) -> Duration {
+    matches
+        .value_of(name)
+        .and_then(|value| parse_duration(value).ok())
+        .unwrap_or(default)
+}
+
+/// Report formats this binary was built with.
+fn formats() -> Vec<&'static str> {
+    let mut formats = vec!["text", "junit", "html"];
+    if cfg!(feature = "json") {
+        formats.push("json");
+    }
+    formats
+}
+
+/// Runtimes this platform can use. The others unpack a rootfs, which needs unix.
+fn runtimes() -> Vec<&'static str> {
+    let mut runtimes = vec!["docker"];
+    if cfg!(unix) {
+        runtimes.extend(&["oci", "chroot"]);
+    }
+    runtimes
+}
+
+fn render(format: &str, report: &Report) -> String {
+    match format {
+        "junit" => report.to_junit(),
+        "html" => report.to_html(),
+        #[cfg(feature = "json")]
+        "json" => report.to_json(),
+        _ => unreachable!("unsupported format {}", format),
+    }
+}
+
+/// Prints a unified diff with removed lines red, added lines green and hunk headers cyan.
+fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        if line.starts_with("@@") {
+            println!("{}", line.cyan());
+        } else if line.starts_with('-') {
+            println!("{}", line.red());
+        } else if line.starts_with('+') {
+            println!("{}", line.green());
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+fn print_text(
+    histories: &[ImageLayer],
+    bisection: Bisection,
+    trunc_size: usize,
+    diff_context: usize,
+) {
     println!();
     println!("{}", "\nResults ==>".bold());
     println!();
 
     let mut printed_height = 0;
-    match results {
-        Ok(mut transitions) => {
-            transitions.sort_by(|t1, t2| t1.after.layer.height.cmp(&t2.after.layer.height));
-
-            for transition in transitions {
-                //print previous steps...
-                if printed_height < transition.after.layer.height {
-                    for (i, layer) in histories
-                        .iter()
-                        .rev()
-                        .enumerate()
-                        .skip(printed_height + 1)
-                        .take(transition.after.layer.height - (printed_height + 1))
-                    {
-                        println!("{}: {}", i, truncate(&layer.created_by, trunc_size).bold());
-                    }
-                }
-
-                println!(
-                    "{}: {} CAUSED:\n\n {}",
-                    transition.after.layer.height,
-                    truncate(&transition.after.layer.creation_command, trunc_size).bold(),
-                    transition.after.result
-                );
-                printed_height = transition.after.layer.height;
+    for transition in bisection.transitions {
+        //print previous steps...
+        if printed_height < transition.after.layer.height {
+            for (i, layer) in histories
+                .iter()
+                .rev()
+                .enumerate()
+                .skip(printed_height + 1)
+                .take(transition.after.layer.height - (printed_height + 1))
+            {
+                println!("{}: {}", i, truncate(&layer.created_by, trunc_size).bold());
             }
         }
-        Err(e) => {
-            println!("{:?}", e);
-            std::process::exit(-1);
+
+        println!(
+            "{}: {} CAUSED:\n",
+            transition.after.layer.height,
+            truncate(&transition.after.layer.creation_command, trunc_size).bold(),
+        );
+        if transition.before.is_some() {
+            print_diff(&transition.diff(diff_context));
+        } else {
+            println!(" {}", transition.after.result);
+        }
+        if let Some(code) = transition.after.exit_code {
+            println!(" (exit code {})", code);
+        }
+        if transition.after.timed_out {
+            println!(" (timed out)");
         }
+        if let Some(verdict) = transition.after.verdict {
+            println!(" ({})", verdict);
+        }
+        printed_height = transition.after.layer.height;
     }
     //print any training steps...
     if printed_height < histories.len() {
@@ -121,4 +678,20 @@ fn main() {
             println!("{}: {}", i, truncate(&layer.created_by, trunc_size).bold());
         }
     }
+
+    if !bisection.flaky.is_empty() {
+        println!();
+        println!(
+            "{}",
+            "Flaky layers (runs disagreed, so they were skipped):".bold()
+        );
+        println!();
+        for layer in bisection.flaky {
+            println!(
+                "{:<3}: {}",
+                layer.height,
+                truncate(&layer.creation_command, trunc_size)
+            );
+        }
+    }
 }
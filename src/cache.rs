@@ -0,0 +1,194 @@
+//! On-disk cache of layer results so that re-running with different comparison
+//! settings doesn't start every container again.
+//!
+//! Entries are keyed by the layer id and a fingerprint of everything that changes what
+//! happens inside the container (command line, timeout, ...). Normalisation and
+//! classification are applied after a result is read back, so they are deliberately not
+//! part of the key: refining a `--replace` regex reuses the cached output.
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::Error;
+use std::path::PathBuf;
+
+use super::{ActionError, ContainerAction, Layer, LayerResult};
+
+/// `$XDG_CACHE_HOME/docker-bisect`, falling back to `~/.cache/docker-bisect`.
+pub fn default_cache_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+    Some(base.join("docker-bisect"))
+}
+
+/// Hashes the parts into a hex string. (`DefaultHasher` may change between Rust releases,
+/// which only costs a cache miss.)
+pub(crate) fn fingerprint<H: Hash>(parts: &H) -> String {
+    let mut hasher = DefaultHasher::new();
+    parts.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Wraps a `ContainerAction`, answering from the cache where it can.
+/// Only results with an exit code are stored; failures to start and timeouts are always
+/// retried.
+#[derive(Clone)]
+pub(crate) struct CachedAction<T> {
+    pub(crate) inner: T,
+    pub(crate) dir: PathBuf,
+    pub(crate) fingerprint: String,
+}
+
+impl<T> CachedAction<T> {
+    fn path(&self, layer: &Layer) -> PathBuf {
+        self.dir
+            .join(fingerprint(&(&layer.image_name, &self.fingerprint)))
+    }
+}
+
+impl<T> ContainerAction for CachedAction<T>
+where
+    T: ContainerAction,
+{
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+        let path = self.path(layer);
+        if let Some(result) = fs::read(&path).ok().and_then(|bytes| decode(&bytes, layer)) {
+            self.inner.skip(1);
+            return Ok(result);
+        }
+
+        let result = self.inner.try_container(layer)?;
+        if result.exit_code.is_some() && !result.timed_out {
+            let stored = fs::create_dir_all(&self.dir).and_then(|_| store(&path, &result));
+            if let Err(e) = stored {
+                eprintln!("Can't write to cache {}: {}", self.dir.display(), e);
+            }
+        }
+        Ok(result)
+    }
+
+    fn skip(&self, count: u64) {
+        self.inner.skip(count);
+    }
+}
+
+/// Writes to a temporary file first so that a parallel run never reads half an entry.
+fn store(path: &PathBuf, result: &LayerResult) -> Result<(), Error> {
+    let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&tmp, encode(result))?;
+    fs::rename(&tmp, path)
+}
+
+/// Layout: the exit code on the first line, then stdout, stderr and the interleaved
+/// output, each as a byte length on its own line followed by that many bytes.
+fn encode(result: &LayerResult) -> Vec<u8> {
+    let mut bytes = format!(
+        "{}\n",
+        result.exit_code.map(|c| c.to_string()).unwrap_or_default()
+    )
+    .into_bytes();
+    for text in &[&result.stdout, &result.stderr, &result.result] {
+        bytes.extend_from_slice(format!("{}\n", text.len()).as_bytes());
+        bytes.extend_from_slice(text.as_bytes());
+    }
+    bytes
+}
+
+fn decode(mut bytes: &[u8], layer: &Layer) -> Option<LayerResult> {
+    fn line<'a>(bytes: &mut &'a [u8]) -> Option<&'a str> {
+        let end = bytes.iter().position(|b| *b == b'\n')?;
+        let text = std::str::from_utf8(&bytes[..end]).ok()?;
+        *bytes = &bytes[end + 1..];
+        Some(text)
+    }
+    fn text(bytes: &mut &[u8]) -> Option<String> {
+        let len: usize = line(bytes)?.parse().ok()?;
+        if bytes.len() < len {
+            return None;
+        }
+        let text = String::from_utf8(bytes[..len].to_vec()).ok()?;
+        *bytes = &bytes[len..];
+        Some(text)
+    }
+
+    let exit_code = line(&mut bytes)?.parse().ok()?;
+    Some(LayerResult::new(
+        layer.clone(),
+        text(&mut bytes)?,
+        text(&mut bytes)?,
+        text(&mut bytes)?,
+        Some(exit_code),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[derive(Clone)]
+    struct CountingAction {
+        runs: Arc<AtomicUsize>,
+    }
+
+    impl ContainerAction for CountingAction {
+        fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+            self.runs.fetch_add(1, Ordering::SeqCst);
+            Ok(LayerResult::new(
+                layer.clone(),
+                "out\n".into(),
+                "err\n".into(),
+                "out\nerr\n".into(),
+                Some(3),
+            ))
+        }
+    }
+
+    fn layer() -> Layer {
+        Layer {
+            height: 2,
+            image_name: "sha256:abc".into(),
+            creation_command: "RUN true".into(),
+        }
+    }
+
+    #[test]
+    fn second_run_comes_from_cache() {
+        let dir = env::temp_dir().join(format!("docker-bisect-test-{}", std::process::id()));
+        let action = CachedAction {
+            inner: CountingAction {
+                runs: Arc::default(),
+            },
+            dir: dir.clone(),
+            fingerprint: fingerprint(&vec!["ls", "-l"]),
+        };
+
+        let first = action.try_container(&layer()).unwrap();
+        let second = action.try_container(&layer()).unwrap();
+        let other_command = CachedAction {
+            fingerprint: fingerprint(&vec!["ls"]),
+            ..action.clone()
+        };
+        other_command.try_container(&layer()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(first, second);
+        assert_eq!(2, action.inner.runs.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn truncated_entries_are_ignored() {
+        let result = CountingAction {
+            runs: Arc::default(),
+        }
+        .try_container(&layer())
+        .unwrap();
+        let bytes = encode(&result);
+
+        assert_eq!(Some(result), decode(&bytes, &layer()));
+        assert_eq!(None, decode(&bytes[..bytes.len() - 1], &layer()));
+    }
+}
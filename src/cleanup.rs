@@ -0,0 +1,96 @@
+//! Removing the containers made for each layer, even when the run is cut short.
+//! Every container is registered here until it is gone, so that a Ctrl-C handler can
+//! clean up whatever is still around.
+use std::sync::Mutex;
+use std::time::Duration;
+
+use dockworker::Docker;
+
+use super::connect;
+
+/// Containers that haven't been removed yet.
+static LIVE: Mutex<Vec<Live>> = Mutex::new(Vec::new());
+
+struct Live {
+    host: String,
+    id: String,
+    keep: bool,
+}
+
+/// A created container. Dropping it removes the container (unless it is kept), which
+/// also happens when the run panics.
+pub(crate) struct Container<'a> {
+    docker: &'a Docker,
+    pub(crate) id: String,
+    keep: bool,
+}
+
+impl<'a> Container<'a> {
+    pub(crate) fn track(docker: &'a Docker, host: &str, id: String, keep: bool) -> Container<'a> {
+        LIVE.lock().expect("live containers lock").push(Live {
+            host: host.to_string(),
+            id: id.clone(),
+            keep,
+        });
+        Container { docker, id, keep }
+    }
+}
+
+impl<'a> Drop for Container<'a> {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = self
+                .docker
+                .remove_container(&self.id, None, Some(true), None);
+        }
+        LIVE.lock()
+            .expect("live containers lock")
+            .retain(|live| live.id != self.id);
+    }
+}
+
+/// Stops every container that is still running and removes the ones that aren't kept.
+/// Meant for a Ctrl-C handler: a bisect that is still going can't carry on afterwards.
+pub fn clean_up_containers() {
+    let live: Vec<Live> = LIVE
+        .lock()
+        .expect("live containers lock")
+        .drain(..)
+        .collect();
+    for container in live {
+        let docker = match connect(Some(&container.host)) {
+            Ok(docker) => docker,
+            Err(_) => continue,
+        };
+        let _ = if container.keep {
+            docker.stop_container(&container.id, Duration::from_secs(0))
+        } else {
+            docker.remove_container(&container.id, None, Some(true), None)
+        };
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    fn tracked() -> Vec<String> {
+        LIVE.lock().unwrap().iter().map(|l| l.id.clone()).collect()
+    }
+
+    #[test]
+    fn containers_are_tracked_until_removed() {
+        let host = "unix:///nonexistent/docker.sock";
+        let docker = Docker::connect_with_unix(host).unwrap();
+
+        let removed = Container::track(&docker, host, "removed".into(), false);
+        let interrupted = Container::track(&docker, host, "interrupted".into(), true);
+        assert_eq!(vec!["removed", "interrupted"], tracked());
+
+        drop(removed);
+        assert_eq!(vec!["interrupted"], tracked());
+        clean_up_containers();
+        assert!(tracked().is_empty());
+        drop(interrupted);
+    }
+}
@@ -0,0 +1,341 @@
+//! Line diffs of command output between two layers.
+
+/// One line of a diff.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Line<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// A shortest edit script between the lines, found with Myers' linear space
+/// algorithm, so memory stays proportional to the output's length however big it is.
+pub(crate) fn lines<'a>(before: &'a str, after: &'a str) -> Vec<Line<'a>> {
+    let a: Vec<&str> = before.lines().collect();
+    let b: Vec<&str> = after.lines().collect();
+    let max_d = max_d(a.len(), b.len());
+    let (mut forward, mut backward) = (Diagonals::new(max_d), Diagonals::new(max_d));
+    let mut diff = Vec::with_capacity(a.len().max(b.len()));
+    conquer(&a, &b, &mut forward, &mut backward, &mut diff);
+    // Removed lines go before the lines that replace them, as in diff(1).
+    for changes in diff.split_mut(is_same) {
+        changes.sort_by_key(is_added);
+    }
+    diff
+}
+
+/// More edits than a shortest edit script between `n` and `m` lines can need on either
+/// side of its middle.
+fn max_d(n: usize, m: usize) -> usize {
+    (n + m).div_ceil(2) + 1
+}
+
+/// The furthest x reached on each diagonal k = x - y, for k in `-max_d..=max_d`.
+struct Diagonals {
+    offset: isize,
+    x: Vec<usize>,
+}
+
+impl Diagonals {
+    fn new(max_d: usize) -> Diagonals {
+        Diagonals {
+            offset: max_d as isize,
+            x: vec![0; 2 * max_d + 1],
+        }
+    }
+}
+
+impl std::ops::Index<isize> for Diagonals {
+    type Output = usize;
+
+    fn index(&self, k: isize) -> &usize {
+        &self.x[(k + self.offset) as usize]
+    }
+}
+
+impl std::ops::IndexMut<isize> for Diagonals {
+    fn index_mut(&mut self, k: isize) -> &mut usize {
+        &mut self.x[(k + self.offset) as usize]
+    }
+}
+
+/// Diffs `a` against `b` by splitting both at the middle of a shortest edit script.
+fn conquer<'a>(
+    a: &[&'a str],
+    b: &[&'a str],
+    forward: &mut Diagonals,
+    backward: &mut Diagonals,
+    diff: &mut Vec<Line<'a>>,
+) {
+    let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+    diff.extend(a[..prefix].iter().map(|l| Line::Same(l)));
+    let (a, b) = (&a[prefix..], &b[prefix..]);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    let (same, a, b) = (
+        &a[a.len() - suffix..],
+        &a[..a.len() - suffix],
+        &b[..b.len() - suffix],
+    );
+
+    if a.is_empty() || b.is_empty() {
+        diff.extend(a.iter().map(|l| Line::Removed(l)));
+        diff.extend(b.iter().map(|l| Line::Added(l)));
+    } else {
+        let (x, y) = middle_snake(a, b, forward, backward);
+        conquer(&a[..x], &b[..y], forward, backward, diff);
+        conquer(&a[x..], &b[y..], forward, backward, diff);
+    }
+    diff.extend(same.iter().map(|l| Line::Same(l)));
+}
+
+/// Where the middle snake of a shortest edit script from `a` to `b` starts. `a` and `b`
+/// must be non-empty and differ in their first and last lines, so that the point is
+/// neither the start nor the end.
+fn middle_snake(
+    a: &[&str],
+    b: &[&str],
+    forward: &mut Diagonals,
+    backward: &mut Diagonals,
+) -> (usize, usize) {
+    let (n, m) = (a.len(), b.len());
+    let delta = n as isize - m as isize;
+    let odd = delta & 1 == 1;
+    forward[1] = 0;
+    backward[1] = 0;
+    let max_d = max_d(n, m) as isize;
+    for d in 0..max_d {
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && forward[k - 1] < forward[k + 1]) {
+                forward[k + 1]
+            } else {
+                forward[k - 1] + 1
+            };
+            let y = (x as isize - k) as usize;
+            let start = (x, y);
+            if x < n && y < m {
+                x += a[x..]
+                    .iter()
+                    .zip(&b[y..])
+                    .take_while(|(p, q)| p == q)
+                    .count();
+            }
+            forward[k] = x;
+            if odd && (k - delta).abs() < d && forward[k] + backward[-(k - delta)] >= n {
+                return start;
+            }
+        }
+        for k in (-d..=d).rev().step_by(2) {
+            let mut x = if k == -d || (k != d && backward[k - 1] < backward[k + 1]) {
+                backward[k + 1]
+            } else {
+                backward[k - 1] + 1
+            };
+            let mut y = (x as isize - k) as usize;
+            if x < n && y < m {
+                let snake = a[..n - x]
+                    .iter()
+                    .rev()
+                    .zip(b[..m - y].iter().rev())
+                    .take_while(|(p, q)| p == q)
+                    .count();
+                x += snake;
+                y += snake;
+            }
+            backward[k] = x;
+            if !odd && (k - delta).abs() <= d && backward[k] + forward[-(k - delta)] >= n {
+                return (n - x, m - y);
+            }
+        }
+    }
+    unreachable!("every pair of sequences has an edit script of at most n + m")
+}
+
+/// A unified diff (without file headers) with `context` unchanged lines around each
+/// change. Changes closer together than that share a hunk.
+pub(crate) fn unified(before: &str, after: &str, context: usize) -> String {
+    let diff = lines(before, after);
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for (i, _) in diff.iter().enumerate().filter(|(_, l)| !is_same(l)) {
+        let (start, end) = (i.saturating_sub(context), (i + context + 1).min(diff.len()));
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let mut text = String::new();
+    for (start, end) in hunks {
+        let old = |range: &[Line]| range.iter().filter(|l| !is_added(l)).count();
+        let new = |range: &[Line]| range.iter().filter(|l| !is_removed(l)).count();
+        text += &format!(
+            "@@ -{} +{} @@\n",
+            hunk_range(old(&diff[..start]), old(&diff[start..end])),
+            hunk_range(new(&diff[..start]), new(&diff[start..end]))
+        );
+        for line in &diff[start..end] {
+            let (prefix, line) = match line {
+                Line::Same(l) => (' ', l),
+                Line::Removed(l) => ('-', l),
+                Line::Added(l) => ('+', l),
+            };
+            text.push(prefix);
+            text.push_str(line);
+            text.push('\n');
+        }
+    }
+    text
+}
+
+/// `start,len` as in GNU diff: the length is left out when it's 1, and an empty range
+/// starts at the line before it.
+fn hunk_range(before: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", before),
+        1 => format!("{}", before + 1),
+        _ => format!("{},{}", before + 1, len),
+    }
+}
+
+fn is_same(line: &Line) -> bool {
+    matches!(line, Line::Same(_))
+}
+
+fn is_added(line: &Line) -> bool {
+    matches!(line, Line::Added(_))
+}
+
+fn is_removed(line: &Line) -> bool {
+    matches!(line, Line::Removed(_))
+}
+
+/// Pairs each run of removed lines with the added lines that replace it, for showing
+/// the two outputs next to each other.
+pub(crate) fn side_by_side<'a>(
+    before: &'a str,
+    after: &'a str,
+) -> Vec<(Option<Line<'a>>, Option<Line<'a>>)> {
+    fn flush<'a>(
+        rows: &mut Vec<(Option<Line<'a>>, Option<Line<'a>>)>,
+        removed: &mut Vec<Line<'a>>,
+        added: &mut Vec<Line<'a>>,
+    ) {
+        for i in 0..removed.len().max(added.len()) {
+            rows.push((removed.get(i).cloned(), added.get(i).cloned()));
+        }
+        removed.clear();
+        added.clear();
+    }
+
+    let mut rows = Vec::new();
+    let (mut removed, mut added) = (Vec::new(), Vec::new());
+    for line in lines(before, after) {
+        match line {
+            Line::Removed(_) => removed.push(line),
+            Line::Added(_) => added.push(line),
+            Line::Same(_) => {
+                flush(&mut rows, &mut removed, &mut added);
+                rows.push((Some(line), Some(line)));
+            }
+        }
+    }
+    flush(&mut rows, &mut removed, &mut added);
+    rows
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changed_line_in_the_middle() {
+        assert_eq!(
+            vec![
+                Line::Same("a"),
+                Line::Removed("b"),
+                Line::Added("B"),
+                Line::Same("c"),
+                Line::Added("d"),
+            ],
+            lines("a\nb\nc", "a\nB\nc\nd")
+        );
+    }
+
+    #[test]
+    fn unified_splits_distant_changes_into_hunks() {
+        let before = "1\n2\n3\n4\n5\n6\n7\n8";
+        let after = "1\nTWO\n3\n4\n5\n6\n7";
+        assert_eq!(
+            "@@ -1,3 +1,3 @@\n 1\n-2\n+TWO\n 3\n@@ -7,2 +7 @@\n 7\n-8\n",
+            unified(before, after, 1)
+        );
+        assert_eq!("@@ -0,0 +1 @@\n+new\n", unified("", "new", 3));
+        assert_eq!("", unified("same", "same", 3));
+    }
+
+    /// Length of the longest common subsequence, the slow way.
+    fn lcs(a: &[&str], b: &[&str]) -> usize {
+        let mut common = vec![vec![0; b.len() + 1]; a.len() + 1];
+        for i in (0..a.len()).rev() {
+            for j in (0..b.len()).rev() {
+                common[i][j] = if a[i] == b[j] {
+                    common[i + 1][j + 1] + 1
+                } else {
+                    common[i + 1][j].max(common[i][j + 1])
+                };
+            }
+        }
+        common[0][0]
+    }
+
+    #[test]
+    fn diffs_are_shortest_and_big_outputs_fit() {
+        // Small pseudo-random outputs over a few distinct lines, so there are many ways
+        // to line them up.
+        let mut seed = 7u32;
+        let mut output = |len: u32| -> String {
+            (0..len)
+                .map(|_| {
+                    seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                    ["a", "b", "c", "d"][(seed >> 16) as usize % 4]
+                })
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        for len in 0..40 {
+            let (before, after) = (output(len), output(40 - len));
+            let diff = lines(&before, &after);
+            let old: Vec<&str> = diff
+                .iter()
+                .filter_map(|l| match l {
+                    Line::Same(l) | Line::Removed(l) => Some(*l),
+                    Line::Added(_) => None,
+                })
+                .collect();
+            let new: Vec<&str> = diff
+                .iter()
+                .filter_map(|l| match l {
+                    Line::Same(l) | Line::Added(l) => Some(*l),
+                    Line::Removed(_) => None,
+                })
+                .collect();
+            assert_eq!(before.lines().collect::<Vec<_>>(), old);
+            assert_eq!(after.lines().collect::<Vec<_>>(), new);
+            let same = diff.iter().filter(|l| is_same(l)).count();
+            assert_eq!(lcs(&old, &new), same, "{:?} -> {:?}", before, after);
+        }
+
+        // A table for these would take gigabytes.
+        let before: Vec<String> = (0..200_000).map(|i| i.to_string()).collect();
+        let mut after = before.clone();
+        after[100] = "changed".into();
+        after.remove(150_000);
+        let (before, after) = (before.join("\n"), after.join("\n"));
+        let diff = lines(&before, &after);
+        assert_eq!(3, diff.iter().filter(|l| !is_same(l)).count());
+    }
+}
@@ -0,0 +1,134 @@
+//! Everything that can stop a bisect before it has an answer.
+use std::error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use super::Layer;
+
+/// Why a `ContainerAction` couldn't get a result for a layer. Any error converts into it
+/// with `?`, as does a `String` or `&str` message. A `BisectError` returned this way is
+/// passed on as it is, anything else becomes `BisectError::Action`. Failures that only
+/// concern one layer (`ContainerCreateFailed`, `Timeout` and `Action`) skip that layer;
+/// any other error stops the bisect.
+pub type ActionError = Box<dyn error::Error + Send + Sync>;
+
+/// Why bisecting failed.
+#[derive(Debug)]
+pub enum BisectError {
+    /// The Docker API at `host` couldn't be reached.
+    DaemonUnreachable {
+        host: String,
+        message: String,
+    },
+    /// The daemon doesn't have the image, or it couldn't be saved.
+    ImageNotFound {
+        image: String,
+        message: String,
+    },
+    /// No container could be made for the layer (the layer couldn't be loaded, or the
+    /// daemon or runtime refused to create it). A command that doesn't exist in the
+    /// layer is not this error: that is a result like any other.
+    ContainerCreateFailed {
+        layer: Layer,
+        message: String,
+    },
+    /// The command ran past its timeout and its container couldn't be stopped.
+    Timeout {
+        layer: Layer,
+        timeout: Duration,
+    },
+    /// Fewer than two layers were left to bisect.
+    NotEnoughLayers(usize),
+    /// Every layer was skipped, so there is nothing to compare.
+    AllSkipped,
+    /// A `good` or `bad` layer (height or id) isn't in the image.
+    LayerNotFound(String),
+    /// A `good` or `bad` id prefix matches more than one layer.
+    AmbiguousLayer(String),
+    /// The `good` layer comes after the `bad` layer.
+    GoodAfterBad,
+    /// Runtimes other than docker unpack the layers from an image archive, and there
+    /// wasn't one.
+    ArchiveRequired,
+    /// The classifier couldn't decide on the layer's result.
+    ClassifierFailed {
+        layer: Layer,
+        message: String,
+    },
+    /// A custom `ContainerAction` failed on the layer.
+    Action {
+        layer: Layer,
+        source: ActionError,
+    },
+    Io(io::Error),
+}
+
+impl fmt::Display for BisectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BisectError::DaemonUnreachable { host, message } => write!(
+                f,
+                "can't connect to {} (is the docker daemon running?): {}",
+                host, message
+            ),
+            BisectError::ImageNotFound { image, message } => {
+                write!(f, "image {} not found: {}", image, message)
+            }
+            BisectError::ContainerCreateFailed { layer, message } => write!(
+                f,
+                "can't create a container for layer {} ({}): {}",
+                layer.height, layer.image_name, message
+            ),
+            BisectError::Timeout { layer, timeout } => write!(
+                f,
+                "layer {} ran past the {:?} timeout and its container couldn't be stopped",
+                layer.height, timeout
+            ),
+            BisectError::NotEnoughLayers(count) => {
+                write!(f, "{} layers found - not enough layers to bisect", count)
+            }
+            BisectError::AllSkipped => write!(f, "every layer was skipped"),
+            BisectError::LayerNotFound(reference) => {
+                write!(f, "layer {} not found in image", reference)
+            }
+            BisectError::AmbiguousLayer(reference) => {
+                write!(f, "layer {} matches more than one layer id", reference)
+            }
+            BisectError::GoodAfterBad => {
+                write!(f, "the good layer must come before the bad layer")
+            }
+            BisectError::ArchiveRequired => write!(
+                f,
+                "runtimes other than docker need an image archive to unpack layers from"
+            ),
+            BisectError::ClassifierFailed { layer, message } => {
+                write!(
+                    f,
+                    "classifier failed on layer {}: {}",
+                    layer.height, message
+                )
+            }
+            BisectError::Action { layer, source } => {
+                write!(f, "layer {}: {}", layer.height, source)
+            }
+            BisectError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for BisectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BisectError::Action { source, .. } => Some(source.as_ref()),
+            BisectError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BisectError {
+    fn from(err: io::Error) -> Self {
+        BisectError::Io(err)
+    }
+}
@@ -0,0 +1,194 @@
+//! Finding the Docker API to talk to: the docker daemon, or the Docker-compatible
+//! socket of a rootless Podman.
+use std::env;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use dockworker::Docker;
+
+use super::BisectError;
+
+const DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
+/// Where to connect: `host` if given, then `$DOCKER_HOST`, then the docker socket if it
+/// exists, then the rootless Podman socket at `$XDG_RUNTIME_DIR/podman/podman.sock`.
+/// Bare paths are taken to be unix sockets.
+pub fn default_host(host: Option<&str>) -> String {
+    resolve(
+        host,
+        env::var("DOCKER_HOST").ok(),
+        env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from),
+        Path::exists,
+    )
+}
+
+fn resolve<F>(
+    host: Option<&str>,
+    docker_host: Option<String>,
+    runtime_dir: Option<PathBuf>,
+    exists: F,
+) -> String
+where
+    F: Fn(&Path) -> bool,
+{
+    let chosen = host.map(String::from).or(docker_host);
+    if let Some(host) = chosen.filter(|h| !h.is_empty()) {
+        return if host.starts_with('/') {
+            format!("unix://{}", host)
+        } else {
+            host
+        };
+    }
+    if !exists(Path::new(DOCKER_SOCKET)) {
+        if let Some(podman) = runtime_dir.map(|dir| dir.join("podman").join("podman.sock")) {
+            if exists(&podman) {
+                return format!("unix://{}", podman.display());
+            }
+        }
+    }
+    format!("unix://{}", DOCKER_SOCKET)
+}
+
+/// Where the TLS key and certificates are when `$DOCKER_TLS_VERIFY` is set, as for the
+/// docker client: `$DOCKER_CERT_PATH`, or else `~/.docker`.
+fn cert_path(
+    tls_verify: Option<OsString>,
+    cert_path: Option<OsString>,
+    home: Option<OsString>,
+) -> Option<PathBuf> {
+    tls_verify.filter(|v| !v.is_empty())?;
+    match cert_path.filter(|p| !p.is_empty()) {
+        Some(path) => Some(path.into()),
+        None => Some(PathBuf::from(home.unwrap_or_default()).join(".docker")),
+    }
+}
+
+/// Makes a client for `host` (see `default_host` for what `None` picks).
+/// tcp addresses are spoken to over TLS when `$DOCKER_TLS_VERIFY` is set, with the
+/// `key.pem`, `cert.pem` and `ca.pem` from `$DOCKER_CERT_PATH`, and over plain HTTP
+/// otherwise. TLS needs the `tls` feature.
+pub fn connect(host: Option<&str>) -> Result<Docker, BisectError> {
+    let host = default_host(host);
+    let certs = cert_path(
+        env::var_os("DOCKER_TLS_VERIFY"),
+        env::var_os("DOCKER_CERT_PATH"),
+        env::var_os("HOME"),
+    );
+    let docker = if host.starts_with("unix://") {
+        Docker::connect_with_unix(&host)
+    } else if let (true, Some(certs)) = (host.starts_with("tcp://"), certs) {
+        if cfg!(not(feature = "tls")) {
+            return Err(BisectError::DaemonUnreachable {
+                host,
+                message: "DOCKER_TLS_VERIFY is set, but TLS needs the tls feature".into(),
+            });
+        }
+        Docker::connect_with_ssl(
+            &host,
+            &certs.join("key.pem"),
+            &certs.join("cert.pem"),
+            &certs.join("ca.pem"),
+        )
+    } else if host.starts_with("tcp://") || host.starts_with("http://") {
+        Docker::connect_with_http(&host)
+    } else {
+        return Err(BisectError::DaemonUnreachable {
+            host,
+            message: "unsupported host, expected unix:// or tcp://".into(),
+        });
+    };
+    docker.map_err(|e| BisectError::DaemonUnreachable {
+        message: e.to_string(),
+        host,
+    })
+}
+
+/// Clients for one host, each used by one thread at a time (a `Docker` can't be shared
+/// between threads). They are made when first needed and then reused, so there are only
+/// as many as there are threads using them at once.
+pub(crate) struct Clients {
+    host: String,
+    idle: Mutex<Vec<Docker>>,
+}
+
+impl Clients {
+    /// Connects and pings the daemon once up front, so that a bad host or a daemon that
+    /// isn't running is reported straight away.
+    pub(crate) fn new(host: Option<&str>) -> Result<Clients, BisectError> {
+        let host = default_host(host);
+        let first = connect(Some(&host))?;
+        first.ping().map_err(|e| BisectError::DaemonUnreachable {
+            host: host.clone(),
+            message: e.to_string(),
+        })?;
+        Ok(Clients {
+            host,
+            idle: Mutex::new(vec![first]),
+        })
+    }
+
+    /// Where the clients connect to.
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+
+    /// Runs `f` with a client of its own.
+    pub(crate) fn with<F, R>(&self, f: F) -> Result<R, BisectError>
+    where
+        F: FnOnce(&Docker) -> R,
+    {
+        let idle = self.idle.lock().expect("docker clients lock").pop();
+        let docker = match idle {
+            Some(docker) => docker,
+            None => connect(Some(&self.host))?,
+        };
+        let result = f(&docker);
+        self.idle.lock().expect("docker clients lock").push(docker);
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn explicit_host_then_env_then_sockets() {
+        let run = Some(PathBuf::from("/run/user/1000"));
+        let podman = |p: &Path| p.ends_with("podman.sock");
+        let both = |_: &Path| true;
+
+        assert_eq!(
+            "unix:///tmp/d.sock",
+            resolve(Some("/tmp/d.sock"), Some("tcp://h:2375".into()), run.clone(), both)
+        );
+        assert_eq!(
+            "tcp://h:2375",
+            resolve(None, Some("tcp://h:2375".into()), run.clone(), both)
+        );
+        assert_eq!(
+            "unix:///var/run/docker.sock",
+            resolve(None, None, run.clone(), both)
+        );
+        assert_eq!(
+            "unix:///run/user/1000/podman/podman.sock",
+            resolve(None, None, run, podman)
+        );
+    }
+
+    #[test]
+    fn certificates_only_when_tls_is_verified() {
+        let home = || Some(OsString::from("/home/me"));
+        assert_eq!(None, cert_path(None, Some("/certs".into()), home()));
+        assert_eq!(None, cert_path(Some("".into()), None, home()));
+        assert_eq!(
+            Some(PathBuf::from("/certs")),
+            cert_path(Some("1".into()), Some("/certs".into()), home())
+        );
+        assert_eq!(
+            Some(PathBuf::from("/home/me/.docker")),
+            cert_path(Some("1".into()), None, home())
+        );
+    }
+}
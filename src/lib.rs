@@ -1,22 +1,67 @@
 //! # docker-bisect
 //! `docker-bisect` create assumes that the docker daemon is running and that you have a
 //! docker image with cached layers to probe.
+//!
+//! Other ordered sequences (build snapshots, VM images, ...) can be bisected with
+//! `bisect_sequence` by implementing `ContainerAction` for them.
 extern crate colored;
 extern crate dockworker;
+extern crate flate2;
 extern crate indicatif;
-extern crate rand;
+extern crate regex;
+#[cfg(feature = "serde")]
+extern crate serde;
+extern crate serde_json;
+extern crate tar;
+
+mod archive;
+mod cache;
+mod cleanup;
+mod diff;
+mod error;
+mod host;
+mod limits;
+mod mounts;
+mod normalise;
+mod pool;
+mod report;
+#[cfg(unix)]
+mod rootfs;
 
 use std::clone::Clone;
+use std::collections::HashMap;
+use std::env;
 use std::fmt;
-use std::io::{prelude::*, Error, ErrorKind};
-use std::sync::Arc;
+use std::fs::{self, File};
+use std::io::{prelude::*, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
+use cache::CachedAction;
+use cleanup::Container;
 use colored::*;
 use dockworker::*;
+use host::Clients;
 use indicatif::ProgressBar;
-use rand::Rng;
+use pool::Pool;
+#[cfg(unix)]
+use rootfs::RootfsContainer;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+pub use archive::ImageArchive;
+pub use cache::default_cache_dir;
+pub use cleanup::clean_up_containers;
+pub use error::{ActionError, BisectError};
+pub use host::{connect, default_host};
+pub use limits::{parse_memory, Limits, Network, MAX_PIDS_LIMIT};
+pub use mounts::{FileCopy, Mount};
+pub use normalise::{Normaliser, Rule};
+pub use report::{missing_layers, HistoryEntry, MissingLayer, Report};
 
 /// Truncates a string to a single line with a max width
 /// and removes docker prefixes.
@@ -26,14 +71,12 @@ use rand::Rng;
 /// use docker_bisect::truncate;
 /// let line = "blar #(nop) real command\n line 2";
 /// assert_eq!("real com", truncate(&line, 8));
+/// assert_eq!("", truncate("", 8));
 /// ```
 pub fn truncate(mut s: &str, max_chars: usize) -> &str {
-    s = s.lines().next().expect("nothing to truncate");
-    if s.contains("#(nop) ") {
-        let mut splat = s.split(" #(nop) ");
-        let _ = splat.next();
-        s = splat.next().expect("#(nop) with no command in.");
-        s = s.trim();
+    s = s.lines().next().unwrap_or("");
+    if let Some(index) = s.find("#(nop) ") {
+        s = s[index + "#(nop) ".len()..].trim();
     }
     match s.char_indices().nth(max_chars) {
         None => s,
@@ -41,8 +84,37 @@ pub fn truncate(mut s: &str, max_chars: usize) -> &str {
     }
 }
 
+/// Parses a duration such as `90s`, `5m`, `1h` or `500ms`. A bare number is seconds.
+///
+/// # Example
+/// ```
+/// use docker_bisect::parse_duration;
+/// use std::time::Duration;
+/// assert_eq!(Ok(Duration::from_secs(90)), parse_duration("90s"));
+/// assert_eq!(Ok(Duration::from_secs(300)), parse_duration("5m"));
+/// assert_eq!(Ok(Duration::from_secs(30)), parse_duration("30"));
+/// assert!(parse_duration("5 minutes").is_err());
+/// ```
+pub fn parse_duration(text: &str) -> Result<Duration, String> {
+    let invalid = || format!("{} is not a duration (expected e.g. 90s, 5m or 1h)", text);
+    let split = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return Err(invalid()),
+    };
+    Ok(Duration::from_millis(number.saturating_mul(millis)))
+}
+
 /// A layer in a docker image. (A layer is a set of files changed due to the previous command).
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Layer {
     pub height: usize,
     pub image_name: String,
@@ -55,28 +127,239 @@ impl fmt::Display for Layer {
     }
 }
 
+/// Which parts of a `LayerResult` decide whether two layers behave differently.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CompareMode {
+    /// Only the captured output text (the default).
+    #[default]
+    Output,
+    /// Only the exit code of the command.
+    ExitCode,
+    /// Both the output text and the exit code.
+    Both,
+}
+
+/// Which output stream(s) count as "the output" when comparing layers.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum Streams {
+    Stdout,
+    Stderr,
+    /// Both stdout and stderr must match (the default).
+    #[default]
+    Both,
+}
+
+/// What a `Classifier` made of a layer's result.
+#[derive(Debug, Clone, Copy, Eq, Ord, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Verdict {
+    Good,
+    Bad,
+    /// The layer can't be judged (e.g. it doesn't build); bisect works around it.
+    Skip,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verdict::Good => write!(f, "good"),
+            Verdict::Bad => write!(f, "bad"),
+            Verdict::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+/// How the layers are searched.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BisectMode {
+    /// Find every layer where the result changes (the default).
+    #[default]
+    AllChanges,
+    /// Assume a single regression: binary search for the first layer that differs from the
+    /// good (lowest) layer. Only O(log n) layers are run.
+    FirstBad,
+    /// Run every layer. Slow, but catches changes that are later undone (A -> B -> A)
+    /// which bisecting can't see.
+    Exhaustive,
+}
+
+/// Everything needed to decide whether two layer results differ.
+/// Output is run through `normalise` before it is compared.
+/// If there is a `classifier` its verdicts are compared instead of the output.
+#[derive(Debug, Clone, Default)]
+pub struct Comparison {
+    pub mode: CompareMode,
+    pub streams: Streams,
+    pub normalise: Normaliser,
+    pub classifier: Option<Arc<dyn Classifier>>,
+}
+
+impl Comparison {
+    /// Records the classifier's verdict (if there is a classifier) on a fresh result.
+    fn judge(&self, mut result: LayerResult) -> Result<LayerResult, ActionError> {
+        if let (Some(classifier), None) = (&self.classifier, result.verdict) {
+            let verdict =
+                classifier
+                    .classify(&result)
+                    .map_err(|e| BisectError::ClassifierFailed {
+                        layer: result.layer.clone(),
+                        message: e.to_string(),
+                    })?;
+            result.verdict = Some(verdict);
+        }
+        Ok(result)
+    }
+
+    /// True if the result can't be trusted: the classifier couldn't decide or repeated
+    /// runs disagreed.
+    fn is_skip(&self, result: &LayerResult) -> bool {
+        result.flaky || result.verdict == Some(Verdict::Skip)
+    }
+
+    /// True if the two results are indistinguishable under this comparison.
+    ///
+    /// # Example
+    /// ```
+    /// use docker_bisect::{CompareMode, Comparison, Layer, LayerResult, Streams};
+    /// let layer = Layer { height: 0, image_name: "a".into(), creation_command: "a".into() };
+    /// let ok = LayerResult::new(layer, "hi".into(), "".into(), "hi".into(), Some(0));
+    /// let failed = LayerResult { stderr: "deprecated!".into(), exit_code: Some(1), ..ok.clone() };
+    /// let stdout_only = Comparison { streams: Streams::Stdout, ..Comparison::default() };
+    /// assert!(stdout_only.same(&ok, &failed));
+    /// let exit_code = Comparison { mode: CompareMode::ExitCode, ..Comparison::default() };
+    /// assert!(!exit_code.same(&ok, &failed));
+    /// let hung = LayerResult { timed_out: true, ..ok.clone() };
+    /// assert!(!stdout_only.same(&ok, &hung));
+    /// ```
+    pub fn same(&self, a: &LayerResult, b: &LayerResult) -> bool {
+        if let (Some(x), Some(y)) = (a.verdict, b.verdict) {
+            return x == y;
+        }
+        // Whatever a killed command had printed so far says nothing about the layer.
+        if a.timed_out || b.timed_out {
+            return a.timed_out == b.timed_out;
+        }
+        let same = |x: &str, y: &str| self.normalise.apply(x) == self.normalise.apply(y);
+        let same_output = || match self.streams {
+            Streams::Stdout => same(&a.stdout, &b.stdout),
+            Streams::Stderr => same(&a.stderr, &b.stderr),
+            Streams::Both => same(&a.stdout, &b.stdout) && same(&a.stderr, &b.stderr),
+        };
+        match self.mode {
+            CompareMode::Output => same_output(),
+            CompareMode::ExitCode => a.exit_code == b.exit_code,
+            CompareMode::Both => a.exit_code == b.exit_code && same_output(),
+        }
+    }
+}
+
 /// The stderr/stdout of running the command on a container made of this layer
-/// (on top of all earlier layers). If command hit the timeout the result may be truncated or empty.
+/// (on top of all earlier layers).
+/// `result` holds both streams interleaved in the order they were written.
+/// `exit_code` is `None` if the container could not be started or waited on.
+/// `verdict` is set when a `Classifier` is in use, and is `Skip` for a layer that
+/// couldn't be run at all (`result` then holds the reason).
+/// `flaky` is set when repeated runs on this layer didn't agree.
+/// `timed_out` is set when the command was killed for running past the timeout. Its output
+/// is then cut short, so timed out results are only ever the same as each other.
 #[derive(Debug, Clone, Eq, Ord, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct LayerResult {
     pub layer: Layer,
     pub result: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub exit_code: Option<i32>,
+    pub verdict: Option<Verdict>,
+    pub flaky: bool,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub timed_out: bool,
+}
+
+impl LayerResult {
+    /// The output of a run that finished: no verdict yet, neither flaky nor timed out.
+    /// `result` is both streams interleaved.
+    pub fn new(
+        layer: Layer,
+        stdout: String,
+        stderr: String,
+        result: String,
+        exit_code: Option<i32>,
+    ) -> LayerResult {
+        LayerResult {
+            layer,
+            result,
+            stdout,
+            stderr,
+            exit_code,
+            verdict: None,
+            flaky: false,
+            timed_out: false,
+        }
+    }
 }
 
 impl fmt::Display for LayerResult {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{} | {}", self.layer, self.result)
+        write!(f, "{} | {}", self.layer, self.result)?;
+        if let Some(code) = self.exit_code {
+            write!(f, " | exit code {}", code)?;
+        }
+        if let Some(verdict) = self.verdict {
+            write!(f, " | {}", verdict)?;
+        }
+        if self.flaky {
+            write!(f, " | flaky")?;
+        }
+        if self.timed_out {
+            write!(f, " | timed out")?;
+        }
+        Ok(())
     }
 }
 
+/// What bisecting found: the transitions, and the layers that were skipped because
+/// repeated runs on them disagreed.
+#[derive(Debug)]
+pub struct Bisection {
+    pub transitions: Vec<Transition>,
+    pub flaky: Vec<Layer>,
+}
+
 /// A Transition is the LayerResult of running the command on the lower layer
 /// and of running the command on the higher layer. No-op transitions are not recorded.
 #[derive(Debug, Eq, Ord, PartialOrd, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Transition {
     pub before: Option<LayerResult>,
     pub after: LayerResult,
 }
 
+impl Transition {
+    /// A unified diff of the output before and after this transition, with `context`
+    /// unchanged lines around each change. Empty if there is no earlier layer.
+    ///
+    /// # Example
+    /// ```
+    /// use docker_bisect::{Layer, LayerResult, Transition};
+    /// let result = |height: usize, output: &str| {
+    ///     let layer = Layer { height, image_name: format!("{}", height), creation_command: "RUN".into() };
+    ///     LayerResult::new(layer, output.into(), String::new(), output.into(), Some(0))
+    /// };
+    /// let transition = Transition {
+    ///     before: Some(result(1, "a\nb\nc")),
+    ///     after: result(2, "a\nB\nc"),
+    /// };
+    /// assert_eq!("@@ -2 +2 @@\n-b\n+B\n", transition.diff(0));
+    /// ```
+    pub fn diff(&self, context: usize) -> String {
+        match &self.before {
+            Some(before) => diff::unified(&before.result, &self.after.result, context),
+            None => String::new(),
+        }
+    }
+}
+
 impl fmt::Display for Transition {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &self.before {
@@ -88,177 +371,687 @@ impl fmt::Display for Transition {
 
 /// Starts the bisect operation. Calculates highest and lowest layer result and if they have
 /// different outputs it starts a binary chop to figure out which layer(s) caused the change.
-fn get_changes<T>(layers: Vec<Layer>, action: &T) -> Result<Vec<Transition>, Error>
+/// At most `jobs` containers run at once.
+fn get_changes<T>(
+    layers: Vec<Layer>,
+    action: &T,
+    compare: &Comparison,
+    jobs: usize,
+) -> Result<Vec<Transition>, BisectError>
+where
+    T: ContainerAction + 'static,
+{
+    let pool = Pool::new(action, compare, jobs);
+    let (start, end, history) = probe_endpoints(&layers, &pool, compare)?;
+
+    if compare.same(&start, &end) {
+        return Ok(vec![Transition {
+            before: None,
+            after: end,
+        }]);
+    }
+
+    bisect(history, start, end, &pool, compare)
+}
+
+/// Runs the first and last layers, returning their results and the layers in between.
+fn probe_endpoints<T>(
+    layers: &[Layer],
+    pool: &Pool<T>,
+    compare: &Comparison,
+) -> Result<(LayerResult, LayerResult, Vec<Layer>), BisectError>
 where
     T: ContainerAction + 'static,
 {
-    let first_layer = layers.first().expect("no first layer");
-    let last_layer = layers.last().expect("no last layer");
+    let first_layer = layers.first().expect("no first layer").clone();
+    let last_layer = layers.last().expect("no last layer").clone();
 
-    let first_image_name: String = first_layer.image_name.clone();
-    let last_image_name = &last_layer.image_name;
+    let mut ends = pool.run(&[first_layer, last_layer])?;
+    let mut end = ends.pop().expect("last layer result");
+    let mut start = ends.pop().expect("first layer result");
 
-    let action_c = action.clone();
-    let left_handle = thread::spawn(move || action_c.try_container(&first_image_name));
+    // A layer the classifier skips can't anchor the search, so move inwards.
+    let mut history = Vec::from(&layers[1..layers.len() - 1]);
+    while compare.is_skip(&start) && !history.is_empty() {
+        start = pool.run_one(&history.remove(0))?;
+    }
+    while compare.is_skip(&end) && !history.is_empty() {
+        let layer = history.pop().expect("history not empty");
+        end = pool.run_one(&layer)?;
+    }
+    if compare.is_skip(&start) || compare.is_skip(&end) {
+        return Err(BisectError::AllSkipped);
+    }
+    Ok((start, end, history))
+}
 
-    let end = action.try_container(last_image_name);
-    let start = left_handle.join().expect("first layer execution error!");
+/// Classic good/bad bisection: the first layer is good, the last is bad and there is
+/// assumed to be a single layer in between where the result flips.
+fn first_bad<T>(
+    layers: Vec<Layer>,
+    action: &T,
+    compare: &Comparison,
+    jobs: usize,
+) -> Result<Vec<Transition>, BisectError>
+where
+    T: ContainerAction + 'static,
+{
+    let pool = Pool::new(action, compare, jobs);
+    let (mut good, mut bad, mut history) = probe_endpoints(&layers, &pool, compare)?;
 
-    if start == end {
+    if compare.same(&good, &bad) {
         return Ok(vec![Transition {
             before: None,
-            after: LayerResult {
-                layer: last_layer.clone(),
-                result: start,
-            },
+            after: bad,
         }]);
     }
 
-    bisect(
-        Vec::from(&layers[1..layers.len() - 1]),
-        LayerResult {
-            layer: first_layer.clone(),
-            result: start,
-        },
-        LayerResult {
-            layer: last_layer.clone(),
-            result: end,
-        },
-        action,
-    )
+    while !history.is_empty() {
+        let half = history.len() / 2;
+        let mid_result = pool.run_one(&history[half])?;
+        if compare.is_skip(&mid_result) {
+            history.remove(half);
+        } else if compare.same(&good, &mid_result) {
+            pool.skip(half as u64);
+            good = mid_result;
+            history.drain(..=half);
+        } else {
+            pool.skip((history.len() - half - 1) as u64);
+            bad = mid_result;
+            history.truncate(half);
+        }
+    }
+
+    Ok(vec![Transition {
+        before: Some(good),
+        after: bad,
+    }])
 }
 
+/// Runs the command on every layer, `jobs` at a time. Results come back in layer order.
+fn run_every_layer<T>(
+    layers: &[Layer],
+    action: &T,
+    compare: &Comparison,
+    jobs: usize,
+) -> Result<Vec<LayerResult>, BisectError>
+where
+    T: ContainerAction + 'static,
+{
+    Pool::new(action, compare, jobs).run(layers)
+}
+
+/// Every transition between consecutive results. Skipped results are left out.
+fn transitions_between(
+    results: &[LayerResult],
+    compare: &Comparison,
+) -> Result<Vec<Transition>, BisectError> {
+    let mut transitions = Vec::new();
+    let mut before: Option<&LayerResult> = None;
+    for result in results.iter().filter(|r| !compare.is_skip(r)) {
+        if let Some(prev) = before {
+            if !compare.same(prev, result) {
+                transitions.push(Transition {
+                    before: Some(prev.clone()),
+                    after: result.clone(),
+                });
+            }
+        }
+        before = Some(result);
+    }
+    if transitions.is_empty() {
+        let last = before.ok_or(BisectError::AllSkipped)?;
+        transitions.push(Transition {
+            before: None,
+            after: last.clone(),
+        });
+    }
+    Ok(transitions)
+}
+
+/// Replays already known results instead of running containers.
+#[derive(Clone)]
+struct ReplayAction {
+    results: Arc<HashMap<String, LayerResult>>,
+}
+
+impl ContainerAction for ReplayAction {
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+        Ok(self.results[&layer.image_name].clone())
+    }
+}
+
+/// Bisects the results of an exhaustive run again and returns the heights of the
+/// transitions where bisecting alone would have given a different answer.
+fn bisect_disagreements(
+    results: &[LayerResult],
+    transitions: &[Transition],
+    compare: &Comparison,
+) -> Vec<usize> {
+    let heights = |transitions: &[Transition]| -> Vec<usize> {
+        transitions
+            .iter()
+            .filter(|t| t.before.is_some())
+            .map(|t| t.after.layer.height)
+            .collect()
+    };
+    let expected = heights(transitions);
+
+    let layers: Vec<Layer> = results.iter().map(|r| r.layer.clone()).collect();
+    let replay = ReplayAction {
+        results: Arc::new(
+            results
+                .iter()
+                .map(|r| (r.layer.image_name.clone(), r.clone()))
+                .collect(),
+        ),
+    };
+    let bisected = match get_changes(layers, &replay, compare, 1) {
+        Ok(found) => heights(&found),
+        Err(_) => return expected,
+    };
+
+    let mut disagreements: Vec<usize> = expected
+        .iter()
+        .filter(|h| !bisected.contains(h))
+        .chain(bisected.iter().filter(|h| !expected.contains(h)))
+        .cloned()
+        .collect();
+    disagreements.sort_unstable();
+    disagreements
+}
+
+/// Finds a layer by height, or else by a prefix of its image id that only one layer has.
+fn find_layer(layers: &[Layer], reference: &str) -> Result<usize, BisectError> {
+    let by_height = layers
+        .iter()
+        .position(|layer| layer.height.to_string() == reference);
+    if let Some(index) = by_height {
+        return Ok(index);
+    }
+    let prefix = reference.trim_start_matches("sha256:");
+    let mut matches = layers.iter().enumerate().filter(|(_, layer)| {
+        !prefix.is_empty()
+            && layer
+                .image_name
+                .trim_start_matches("sha256:")
+                .starts_with(prefix)
+    });
+    match (matches.next(), matches.next()) {
+        (Some((index, _)), None) => Ok(index),
+        (Some(_), Some(_)) => Err(BisectError::AmbiguousLayer(reference.to_string())),
+        (None, _) => Err(BisectError::LayerNotFound(reference.to_string())),
+    }
+}
+
+/// Narrows the layers down to those between the known good and known bad layers (inclusive).
+fn pin_layers(
+    layers: Vec<Layer>,
+    good: Option<&str>,
+    bad: Option<&str>,
+) -> Result<Vec<Layer>, BisectError> {
+    let first = match good {
+        Some(good) => find_layer(&layers, good)?,
+        None => 0,
+    };
+    let last = match bad {
+        Some(bad) => find_layer(&layers, bad)?,
+        None => layers.len().saturating_sub(1),
+    };
+    if first > last {
+        return Err(BisectError::GoodAfterBad);
+    }
+    Ok(layers[first..=last].to_vec())
+}
+
+/// A run of untested layers between two results that differ.
+struct Segment {
+    history: Vec<Layer>,
+    start: LayerResult,
+    end: LayerResult,
+}
+
+/// Binary chops every segment until each transition is between neighbouring layers.
+/// Segments are worked on breadth first: each round runs the middle layer of every
+/// open segment as one batch on the pool, lowest layer first.
 fn bisect<T>(
     history: Vec<Layer>,
     start: LayerResult,
     end: LayerResult,
-    action: &T,
-) -> Result<Vec<Transition>, Error>
+    pool: &Pool<T>,
+    compare: &Comparison,
+) -> Result<Vec<Transition>, BisectError>
 where
     T: ContainerAction + 'static,
 {
-    let size = history.len();
-    if size == 0 {
-        if start.result == end.result {
-            return Err(Error::new(std::io::ErrorKind::Other, ""));
+    let mut transitions = Vec::new();
+    let mut segments = vec![Segment {
+        history,
+        start,
+        end,
+    }];
+
+    while !segments.is_empty() {
+        let (done, open): (Vec<Segment>, Vec<Segment>) =
+            segments.into_iter().partition(|s| s.history.is_empty());
+        transitions.extend(done.into_iter().map(|s| Transition {
+            before: Some(s.start),
+            after: s.end,
+        }));
+
+        let mids: Vec<Layer> = open
+            .iter()
+            .map(|s| s.history[s.history.len() / 2].clone())
+            .collect();
+        let mid_results = pool.run(&mids)?;
+
+        segments = Vec::new();
+        for (segment, mid_result) in open.into_iter().zip(mid_results) {
+            let Segment {
+                mut history,
+                start,
+                end,
+            } = segment;
+            let half = history.len() / 2;
+            if compare.is_skip(&mid_result) {
+                history.remove(half);
+                segments.push(Segment {
+                    history,
+                    start,
+                    end,
+                });
+                continue;
+            }
+
+            let upper = history.split_off(half + 1);
+            history.truncate(half);
+            let lower = history;
+            if compare.same(&start, &mid_result) {
+                pool.skip((mid_result.layer.height - start.layer.height) as u64);
+                segments.push(Segment {
+                    history: upper,
+                    start: mid_result,
+                    end,
+                });
+            } else if compare.same(&mid_result, &end) {
+                pool.skip((end.layer.height - mid_result.layer.height) as u64);
+                segments.push(Segment {
+                    history: lower,
+                    start,
+                    end: mid_result,
+                });
+            } else {
+                segments.push(Segment {
+                    history: lower,
+                    start,
+                    end: mid_result.clone(),
+                });
+                segments.push(Segment {
+                    history: upper,
+                    start: mid_result,
+                    end,
+                });
+            }
         }
-        return Ok(vec![Transition {
-            before: Some(start.clone()),
-            after: end.clone(),
-        }]);
     }
 
-    let half = size / 2;
-    let mid_result = LayerResult {
-        layer: history[half].clone(),
-        result: action.try_container(&history[half].image_name),
-    };
+    transitions.sort_by_key(|t| t.after.layer.height);
+    Ok(transitions)
+}
 
-    if size == 1 {
-        let mut results = Vec::<Transition>::new();
-        if *start.result != mid_result.result {
-            results.push(Transition {
-                before: Some(start.clone()),
-                after: mid_result.clone(),
-            });
-        }
-        if mid_result.result != *end.result {
-            results.push(Transition {
-                before: Some(mid_result),
-                after: end.clone(),
-            });
-        }
-        return Ok(results);
+/// Runs the command on one layer of the sequence being bisected. Implement it to bisect
+/// something other than a docker image (build snapshots, VM images, ...) with
+/// `bisect_sequence`. Clones are handed to worker threads, so an action may be asked for
+/// several layers at once.
+pub trait ContainerAction: Clone + Send {
+    /// Runs the command on `layer`. A command that runs and fails is still a result (with
+    /// its exit code); an error means there is no result at all and stops the search.
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError>;
+
+    /// Called with the number of layers the search didn't need to run, for progress
+    /// reporting.
+    fn skip(&self, _count: u64) {}
+
+    /// Identifies everything besides the layer that affects the result (command line,
+    /// timeout, ...), so that results can be cached between runs. Actions without a
+    /// fingerprint are never cached.
+    fn fingerprint(&self) -> Option<String> {
+        None
     }
+}
 
-    if start.result == mid_result.result {
-        action.skip((mid_result.layer.height - start.layer.height) as u64);
-        return bisect(Vec::from(&history[half + 1..]), mid_result, end, action);
+/// Runs each layer several times and marks it flaky if the runs don't agree.
+/// The first run is the one reported.
+#[derive(Clone)]
+struct RepeatAction<T> {
+    inner: T,
+    times: usize,
+    compare: Comparison,
+    flaky: Arc<Mutex<Vec<Layer>>>,
+}
+
+impl<T> ContainerAction for RepeatAction<T>
+where
+    T: ContainerAction,
+{
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+        let mut first = self.compare.judge(self.inner.try_container(layer)?)?;
+        for _ in 1..self.times {
+            let again = self.compare.judge(self.inner.try_container(layer)?)?;
+            if !self.compare.same(&first, &again) {
+                first.flaky = true;
+                self.flaky.lock().expect("flaky lock").push(layer.clone());
+                break;
+            }
+        }
+        Ok(first)
     }
-    if mid_result.result == end.result {
-        action.skip((end.layer.height - mid_result.layer.height) as u64);
-        return bisect(Vec::from(&history[..half]), start, mid_result, action);
+
+    fn skip(&self, count: u64) {
+        self.inner.skip(count);
     }
+}
 
-    let clone_a = action.clone();
-    let clone_b = action.clone();
-    let mid_result_c = mid_result.clone();
+/// Decides whether a layer's result is good or bad, like the script given to `git bisect run`.
+/// When a classifier is used, layers are compared by verdict instead of by output.
+/// An error stops the bisect, as it would most likely fail on every layer.
+pub trait Classifier: fmt::Debug + Send + Sync {
+    fn classify(&self, result: &LayerResult) -> Result<Verdict, ActionError>;
+}
 
-    let hist_a = Vec::from(&history[..half]);
+/// Runs a host-side shell command (`sh -c`, or `cmd /C` on Windows) for each layer result.
+/// The command gets the layer's output on stdin and `DOCKER_BISECT_EXIT_CODE`,
+/// `DOCKER_BISECT_LAYER`, `DOCKER_BISECT_HEIGHT` and `DOCKER_BISECT_TIMED_OUT` (`1` or `0`)
+/// in its environment, and prints `good`, `bad` or `skip`. If it prints none of those, its
+/// exit code decides as for `git bisect run`: 0 is good, 125 is skip and anything else is
+/// bad, except that a command the shell can't find or run is an error.
+#[derive(Debug, Clone)]
+pub struct ScriptClassifier {
+    pub script: String,
+}
 
-    let left_handle = thread::spawn(move || bisect(hist_a, start, mid_result, &clone_a));
-    let right_handle =
-        thread::spawn(move || bisect(Vec::from(&history[half + 1..]), mid_result_c, end, &clone_b));
-    let mut left_results: Vec<Transition> = left_handle
-        .join()
-        .expect("left")
-        .expect("left transition err");
+impl ScriptClassifier {
+    fn shell(&self) -> Command {
+        if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(&self.script);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(&self.script);
+            command
+        }
+    }
+}
 
-    let right_results: Vec<Transition> = right_handle
-        .join()
-        .expect("right")
-        .expect("right transition err");
+impl Classifier for ScriptClassifier {
+    fn classify(&self, result: &LayerResult) -> Result<Verdict, ActionError> {
+        let mut child = self
+            .shell()
+            .env(
+                "DOCKER_BISECT_EXIT_CODE",
+                result.exit_code.map(|c| c.to_string()).unwrap_or_default(),
+            )
+            .env("DOCKER_BISECT_LAYER", &result.layer.image_name)
+            .env("DOCKER_BISECT_HEIGHT", result.layer.height.to_string())
+            .env(
+                "DOCKER_BISECT_TIMED_OUT",
+                if result.timed_out { "1" } else { "0" },
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take();
+        let input = result.result.clone();
+        // Written from another thread so a script that doesn't read stdin can't deadlock us.
+        let writer = thread::spawn(move || {
+            if let Some(mut stdin) = stdin {
+                let _ = stdin.write_all(input.as_bytes());
+            }
+        });
+        let output = child.wait_with_output()?;
+        let _ = writer.join();
+
+        Ok(match String::from_utf8_lossy(&output.stdout).trim() {
+            "good" => Verdict::Good,
+            "bad" => Verdict::Bad,
+            "skip" => Verdict::Skip,
+            _ => match output.status.code() {
+                Some(0) => Verdict::Good,
+                Some(125) => Verdict::Skip,
+                // Not found or not executable (sh), or not recognised (cmd).
+                Some(code @ 126) | Some(code @ 127) | Some(code @ 9009) => {
+                    return Err(
+                        format!("`{}` couldn't be run (exit code {})", self.script, code).into(),
+                    )
+                }
+                _ => Verdict::Bad,
+            },
+        })
+    }
+}
 
-    left_results.extend(right_results); // These results are sorted later...
-    Ok(left_results)
+/// How the command is run, whatever runs the containers.
+#[derive(Clone)]
+pub(crate) struct RunSettings {
+    pub(crate) command_line: Vec<String>,
+    pub(crate) timeout: Duration,
+    pub(crate) kill_signal: String,
+    pub(crate) kill_grace: Duration,
+    pub(crate) env: Vec<String>,
+    pub(crate) workdir: Option<String>,
+    pub(crate) user: Option<String>,
+    pub(crate) entrypoint: Option<String>,
+    pub(crate) mounts: Vec<Mount>,
+    pub(crate) copies: Vec<FileCopy>,
+    pub(crate) limits: Limits,
 }
 
-trait ContainerAction: Clone + Send {
-    fn try_container(&self, container_id: &str) -> String;
-    fn skip(&self, count: u64) -> ();
+impl RunSettings {
+    fn new(command_line: Vec<String>, options: &BisectOptions) -> RunSettings {
+        RunSettings {
+            command_line,
+            timeout: options.timeout,
+            kill_signal: options.kill_signal.clone(),
+            kill_grace: options.kill_grace,
+            env: options.env.clone(),
+            workdir: options.workdir.clone(),
+            user: options.user.clone(),
+            entrypoint: options.entrypoint.clone(),
+            mounts: options.mounts.clone(),
+            copies: options.copies.clone(),
+            limits: options.limits.clone(),
+        }
+    }
+
+    /// Everything that affects what happens inside the container on `runtime`. Files
+    /// from the host can change between runs without the cache knowing, so then nothing
+    /// is cached.
+    pub(crate) fn fingerprint(&self, runtime: &Runtime) -> Option<String> {
+        if !self.mounts.is_empty() || !self.copies.is_empty() {
+            return None;
+        }
+        Some(cache::fingerprint(&(
+            &self.command_line,
+            self.timeout,
+            runtime,
+            &self.env,
+            &self.workdir,
+            &self.user,
+            &self.entrypoint,
+            self.limits.key(),
+        )))
+    }
 }
 
 #[derive(Clone)]
 struct DockerContainer {
     pb: Arc<ProgressBar>,
-    command_line: Vec<String>,
-    timeout_in_seconds: usize,
+    clients: Arc<Clients>,
+    settings: RunSettings,
+    /// Where layers that aren't in the daemon yet are loaded from.
+    archive: Option<Arc<ImageArchive>>,
+    /// Leave containers behind once they have run instead of removing them.
+    keep_containers: bool,
 }
 
 impl DockerContainer {
-    fn new(total: u64, command_line: Vec<String>, timeout_in_seconds: usize) -> DockerContainer {
+    fn new(
+        total: u64,
+        clients: Arc<Clients>,
+        settings: RunSettings,
+        options: &BisectOptions,
+    ) -> DockerContainer {
         let pb = Arc::new(ProgressBar::new(total));
 
         DockerContainer {
             pb,
-            command_line,
-            timeout_in_seconds,
+            clients,
+            settings,
+            archive: options.archive.clone(),
+            keep_containers: options.keep_containers,
         }
     }
-}
 
-struct Guard<'a> { buf: &'a mut Vec<u8>, len: usize }
+    /// Puts the copies into a created container. Docker takes them as a tar file, which
+    /// is extracted at `/`.
+    fn put_copies(&self, docker: &Docker, id: &str) -> Result<(), String> {
+        let path = env::temp_dir().join(format!("docker-bisect-copies-{}.tar", id));
+        let written =
+            File::create(&path).and_then(|file| mounts::write_copies(&self.settings.copies, file));
+        let result = match written {
+            Ok(_) => docker
+                .put_file(id, &path, Path::new("/"), false)
+                .map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let _ = fs::remove_file(&path);
+        result
+    }
+}
 
-impl<'a> Drop for Guard<'a> {
-    fn drop(&mut self) {
-        unsafe { self.buf.set_len(self.len); }
+/// Splits a docker log stream into (stdout, stderr, interleaved).
+///
+/// Without a tty docker frames each chunk with an 8 byte header: the stream type
+/// (1 = stdout, 2 = stderr), three zero bytes and a big-endian u32 payload length.
+/// Anything that doesn't look like a frame is treated as stdout.
+fn demux_log(mut raw: &[u8]) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let mut stdout = Vec::new();
+    let mut stderr = Vec::new();
+    let mut combined = Vec::new();
+    while !raw.is_empty() {
+        let is_frame = raw.len() >= 8 && raw[0] <= 2 && raw[1..4] == [0, 0, 0];
+        if !is_frame {
+            stdout.extend_from_slice(raw);
+            combined.extend_from_slice(raw);
+            break;
+        }
+        let len = u32::from_be_bytes([raw[4], raw[5], raw[6], raw[7]]) as usize;
+        let payload = &raw[8..raw.len().min(8 + len)];
+        if raw[0] == 2 {
+            stderr.extend_from_slice(payload);
+        } else {
+            stdout.extend_from_slice(payload);
+        }
+        combined.extend_from_slice(payload);
+        raw = &raw[8 + payload.len()..];
     }
+    (stdout, stderr, combined)
+}
+
+/// The result for a layer whose container never ran: the error and no exit code.
+fn failed_to_start(layer: &Layer, err: String) -> LayerResult {
+    LayerResult::new(layer.clone(), String::new(), err.clone(), err, None)
 }
 
 impl ContainerAction for DockerContainer {
-    fn try_container(&self, container_id: &str) -> String {
-        let docker: Docker = Docker::connect_with_defaults().expect("docker daemon running?");
-        let container_name: String = rand::thread_rng().gen_range(0., 1.3e4).to_string();
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+        let result = self.clients.with(|docker| self.run(docker, layer));
+        // Once per layer, however far it got.
+        self.pb.inc(1);
+        Ok(result??)
+    }
+
+    fn skip(&self, count: u64) {
+        self.pb.inc(count);
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.settings.fingerprint(&Runtime::Docker)
+    }
+}
+
+impl DockerContainer {
+    fn run(&self, docker: &Docker, layer: &Layer) -> Result<LayerResult, BisectError> {
+        let create_failed = |message: String| BisectError::ContainerCreateFailed {
+            layer: layer.clone(),
+            message,
+        };
+
+        if let Some(archive) = &self.archive {
+            archive
+                .load(docker, layer)
+                .map_err(|e| create_failed(e.to_string()))?;
+        }
 
         //Create container
-        let mut create = ContainerCreateOptions::new(&container_id);
+        let mut create = ContainerCreateOptions::new(&layer.image_name);
         let mut host_config = ContainerHostConfig::new();
         host_config.auto_remove(false);
+        for mount in &self.settings.mounts {
+            host_config.binds(mount.bind());
+        }
+        self.settings.limits.apply(&mut host_config);
         create.host_config(host_config);
-        let it = self.command_line.iter();
+        create.stop_signal(self.settings.kill_signal.clone());
+        for var in &self.settings.env {
+            create.env(var.clone());
+        }
+        if let Some(workdir) = &self.settings.workdir {
+            create.working_dir(workdir.into());
+        }
+        if let Some(user) = &self.settings.user {
+            create.user(user.clone());
+        }
+        if let Some(entrypoint) = &self.settings.entrypoint {
+            // Like `docker run --entrypoint ""`, `[""]` clears the image's entrypoint.
+            create.entrypoint(vec![entrypoint.clone()]);
+        }
+        let it = self.settings.command_line.iter();
         for command in it {
             create.cmd(command.clone());
         }
 
-        let container: CreateContainerResponse = docker
-            .create_container(Some(&container_name), &create)
-            .expect("couldn't create container");
+        let created: CreateContainerResponse =
+            docker.create_container(None, &create).map_err(|e| {
+                let message = e.to_string();
+                if message.starts_with("No such image") {
+                    BisectError::ImageNotFound {
+                        image: layer.image_name.clone(),
+                        message,
+                    }
+                } else {
+                    create_failed(message)
+                }
+            })?;
+        // Removed again when this goes out of scope, however the run ends.
+        let container = Container::track(
+            docker,
+            self.clients.host(),
+            created.id,
+            self.keep_containers,
+        );
 
-        let result = docker.start_container(&container.id);
-        if result.is_err() {
-            let err: dockworker::errors::Error = result.unwrap_err();
+        if !self.settings.copies.is_empty() {
+            self.put_copies(docker, &container.id)
+                .map_err(|e| create_failed(e.to_string()))?;
+        }
 
-            return format!("{}", err);
+        // A command that doesn't exist in this layer fails here, which is a result.
+        let result = docker.start_container(&container.id);
+        if let Err(err) = result {
+            return Ok(failed_to_start(layer, format!("{}", err)));
         }
 
         let log_options = ContainerLogOptions {
@@ -270,112 +1063,392 @@ impl ContainerAction for DockerContainer {
             follow: true,
         };
 
-        let timeout = Duration::from_secs(self.timeout_in_seconds as u64);
-
-        let mut container_output = String::new();
+        let logs = docker
+            .log_container(&container.id, &log_options)
+            .map_err(|err| create_failed(format!("can't read its output: {}", err)))?;
+        // Read on another thread so that the deadline holds even if nothing is written.
+        let raw = Arc::new(Mutex::new(Vec::new()));
+        let (finished, reader_done) = channel();
+        let reader_raw = raw.clone();
+        thread::spawn(move || {
+            let mut logs = logs;
+            let mut buf = [0; 4096];
+            loop {
+                match logs.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => reader_raw
+                        .lock()
+                        .expect("log lock")
+                        .extend_from_slice(&buf[..n]),
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+            let _ = finished.send(());
+        });
+
+        let mut timed_out =
+            reader_done.recv_timeout(self.settings.timeout) == Err(RecvTimeoutError::Timeout);
+        if timed_out {
+            // Sends the kill signal, then SIGKILL once the grace period is over. The API
+            // takes whole seconds, so a shorter grace period is rounded up rather than to 0.
+            let grace = Duration::from_secs(self.settings.kill_grace.as_secs_f64().ceil() as u64);
+            let stopped = docker.stop_container(&container.id, grace);
+            let output_ended = reader_done.recv_timeout(Duration::from_secs(1)).is_ok();
+            if stopped.is_err() {
+                // Docker refuses to stop a container that has already exited. The output
+                // ends when the container does, so if it has then the command finished
+                // on its own just as the deadline passed.
+                if !output_ended {
+                    return Err(BisectError::Timeout {
+                        layer: layer.clone(),
+                        timeout: self.settings.timeout,
+                    });
+                }
+                timed_out = false;
+            }
+        }
+        let exit_code = docker
+            .wait_container(&container.id)
+            .ok()
+            .map(|status| status.into_inner());
+
+        let (stdout, stderr, combined) = demux_log(&raw.lock().expect("log lock"));
+        let output = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+        Ok(LayerResult {
+            timed_out,
+            ..LayerResult::new(
+                layer.clone(),
+                output(&stdout),
+                output(&stderr),
+                output(&combined),
+                exit_code,
+            )
+        })
+    }
+}
 
-        let now = SystemTime::now();
-        let timeout_time = now + timeout;
+/// What runs the containers.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Hash)]
+pub enum Runtime {
+    /// The docker daemon.
+    #[default]
+    Docker,
+    /// An OCI runtime program such as runc or crun, run on a rootfs unpacked from the
+    /// archive. No daemon is needed.
+    #[cfg(unix)]
+    Oci(String),
+    /// A chroot into a rootfs unpacked from the archive, inside a new user namespace
+    /// (`unshare`). No daemon is needed.
+    #[cfg(unix)]
+    Chroot,
+}
 
-        let result = docker.log_container(&container_name, &log_options);
-        if let Ok(result) = result {
-            let mut r = result;
+/// The runner for the chosen runtime.
+#[derive(Clone)]
+enum Runner {
+    Docker(DockerContainer),
+    #[cfg(unix)]
+    Rootfs(RootfsContainer),
+}
 
-            let reservation_size = 32;
-            let mut buf = Vec::<u8>::new();
-            {
-                let mut g = Guard { len: buf.len(), buf: &mut buf };
-                loop {
-                    if g.len == g.buf.len() {
-                        g.buf.resize(g.len + reservation_size, 0);
-                    }
-                    match r.read(&mut g.buf[g.len..]) {
-                        Ok(0) => { break; }
-                        Ok(n) => g.len += n,
-                        Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
-                        Err(_e) => { break; }
-                    }
-                    if SystemTime::now() > timeout_time {
-                        break;
-                    }
-                }
-            }
+impl Runner {
+    fn pb(&self) -> &ProgressBar {
+        match self {
+            Runner::Docker(docker) => &docker.pb,
+            #[cfg(unix)]
+            Runner::Rootfs(rootfs) => &rootfs.pb,
+        }
+    }
+}
 
-            container_output = String::from_utf8_lossy(&buf).to_string();
+impl ContainerAction for Runner {
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+        match self {
+            Runner::Docker(docker) => docker.try_container(layer),
+            #[cfg(unix)]
+            Runner::Rootfs(rootfs) => rootfs.try_container(layer),
         }
+    }
 
-        self.pb.inc(1);
-        let _stop_result = docker.stop_container(&container.id, timeout);
-        container_output
+    fn skip(&self, count: u64) {
+        self.pb().inc(count);
     }
 
-    fn skip(&self, count: u64) -> () {
-        self.pb.inc(count);
+    fn fingerprint(&self) -> Option<String> {
+        match self {
+            Runner::Docker(docker) => docker.fingerprint(),
+            #[cfg(unix)]
+            Runner::Rootfs(rootfs) => rootfs.fingerprint(),
+        }
     }
 }
 
 /// Struct to hold parameters.
 pub struct BisectOptions {
-    pub timeout_in_seconds: usize,
+    /// How long each command may run for.
+    pub timeout: Duration,
+    /// Signal sent to a command that runs past the timeout.
+    pub kill_signal: String,
+    /// How long a command gets to exit after the kill signal before it gets SIGKILL.
+    /// Docker only takes whole seconds, so with docker this is rounded up.
+    pub kill_grace: Duration,
+    /// `KEY=value` variables set for the command, over the image's own.
+    pub env: Vec<String>,
+    /// Working directory for the command instead of the image's.
+    pub workdir: Option<String>,
+    /// User to run the command as (name or uid, optionally with `:group`).
+    pub user: Option<String>,
+    /// Program that runs the command instead of the image's entrypoint. The image's
+    /// entrypoint can change from layer to layer; an empty string runs the command
+    /// without one.
+    pub entrypoint: Option<String>,
+    /// Host paths bind-mounted into every container. Results aren't cached with mounts.
+    pub mounts: Vec<Mount>,
+    /// Host paths copied into every container before it starts. Results aren't cached
+    /// with copies.
+    pub copies: Vec<FileCopy>,
+    /// Network and resource limits for every container.
+    pub limits: Limits,
     pub trunc_size: usize,
+    pub compare: CompareMode,
+    pub streams: Streams,
+    pub normalise: Normaliser,
+    pub classifier: Option<Arc<dyn Classifier>>,
+    pub mode: BisectMode,
+    /// Layer (height or id) to use as the lower endpoint instead of the first layer.
+    pub good: Option<String>,
+    /// Layer (height or id) to use as the upper endpoint instead of the last layer.
+    pub bad: Option<String>,
+    /// How many containers may run at once.
+    pub jobs: usize,
+    /// Where to cache layer results between runs (`None` turns caching off).
+    pub cache_dir: Option<PathBuf>,
+    /// How many times to run each layer. Layers whose runs disagree are flaky and are
+    /// skipped by the search. Repeated runs bypass the cache.
+    pub repeat: usize,
+    /// Rebuilds the layers from a saved image instead of using the history ids, so that
+    /// layers without an id can be bisected too.
+    pub archive: Option<Arc<ImageArchive>>,
+    /// What runs the containers. Runtimes other than docker need an `archive`.
+    pub runtime: Runtime,
+    /// The Docker API to use, as `unix://` or `tcp://` (`None` picks `default_host`).
+    pub host: Option<String>,
+    /// Leave the containers behind instead of removing them, for debugging.
+    pub keep_containers: bool,
+}
+
+impl Default for BisectOptions {
+    fn default() -> Self {
+        BisectOptions {
+            timeout: Duration::from_secs(10),
+            kill_signal: "SIGTERM".into(),
+            kill_grace: Duration::from_secs(10),
+            env: Vec::new(),
+            workdir: None,
+            user: None,
+            entrypoint: None,
+            mounts: Vec::new(),
+            copies: Vec::new(),
+            limits: Limits::default(),
+            trunc_size: 100,
+            compare: CompareMode::default(),
+            streams: Streams::default(),
+            normalise: Normaliser::default(),
+            classifier: None,
+            mode: BisectMode::default(),
+            good: None,
+            bad: None,
+            jobs: 4,
+            cache_dir: None,
+            repeat: 1,
+            archive: None,
+            runtime: Runtime::default(),
+            host: None,
+            keep_containers: false,
+        }
+    }
 }
 
 /// Create containers based on layers and run command_line against them.
 /// Result is the differences in std out and std err.
+/// Progress is written to stderr so that stdout is left for the results.
 pub fn try_bisect(
-    histories: &Vec<ImageLayer>,
+    histories: &[ImageLayer],
     command_line: Vec<String>,
     options: BisectOptions,
-) -> Result<Vec<Transition>, Error> {
-    println!(
+) -> Result<Bisection, BisectError> {
+    eprintln!(
         "\n{}\n\n{:?}\n",
         "Command to apply to layers:".bold(),
         &command_line
     );
-    let create_and_try_container = DockerContainer::new(
-        histories.len() as u64,
-        command_line,
-        options.timeout_in_seconds,
-    );
-
-    println!("{}", "Skipped missing layers:".bold());
-    println!();
+    let total = (histories.len() * options.repeat.max(1)) as u64;
+    let settings = RunSettings::new(command_line, &options);
+    let create_and_try_container = match (&options.runtime, &options.archive) {
+        (Runtime::Docker, _) => {
+            let clients = Clients::new(options.host.as_deref())?;
+            Runner::Docker(DockerContainer::new(
+                total,
+                Arc::new(clients),
+                settings,
+                &options,
+            ))
+        }
+        #[cfg(unix)]
+        (runtime, Some(archive)) => Runner::Rootfs(RootfsContainer {
+            pb: Arc::new(ProgressBar::new(total)),
+            settings,
+            archive: archive.clone(),
+            runtime: runtime.clone(),
+        }),
+        #[cfg(unix)]
+        (_, None) => return Err(BisectError::ArchiveRequired),
+    };
 
     let mut layers = Vec::new();
-    for (index, event) in histories.iter().rev().enumerate() {
-        let mut created = event.created_by.clone();
-        created = truncate(&created, options.trunc_size).to_string();
-        match event.id.clone() {
-            Some(layer_name) => layers.push(Layer {
-                height: index,
-                image_name: layer_name,
-                creation_command: event.created_by.clone(),
-            }),
-            None => println!("{:<3}: {}.", index, truncate(&created, options.trunc_size)),
+    if let Some(archive) = &options.archive {
+        layers = archive.layers();
+    } else {
+        eprintln!("{}", "Skipped missing layers:".bold());
+        eprintln!();
+
+        for (index, event) in histories.iter().rev().enumerate() {
+            let mut created = event.created_by.clone();
+            created = truncate(&created, options.trunc_size).to_string();
+            match event.id.clone() {
+                Some(layer_name) => layers.push(Layer {
+                    height: index,
+                    image_name: layer_name,
+                    creation_command: event.created_by.clone(),
+                }),
+                None => eprintln!("{:<3}: {}.", index, truncate(&created, options.trunc_size)),
+            }
         }
     }
 
-    println!();
-    println!(
+    eprintln!();
+    eprintln!(
         "{}",
         "Bisecting found layers (running command on the layers) ==>\n".bold()
     );
 
+    let results = bisect_sequence(layers, &create_and_try_container, &options);
+    create_and_try_container.pb().finish_with_message("done");
+    results
+}
+
+/// Bisects any ordered sequence, not just the layers of a docker image: `action` runs the
+/// command on each item, and the search, comparison, caching and repeats are as set in
+/// `options` (the container settings there are not used). Items go from oldest to newest
+/// with increasing heights, and their `image_name` identifies them for `good`, `bad` and
+/// the cache.
+///
+/// # Example
+/// ```
+/// use docker_bisect::{
+///     bisect_sequence, ActionError, BisectOptions, ContainerAction, Layer, LayerResult,
+/// };
+///
+/// /// Pretends that snapshot 3 broke the build.
+/// #[derive(Clone)]
+/// struct Snapshot;
+///
+/// impl ContainerAction for Snapshot {
+///     fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+///         let output = if layer.height < 3 { "ok" } else { "broken" };
+///         Ok(LayerResult::new(layer.clone(), output.into(), String::new(), output.into(), Some(0)))
+///     }
+/// }
+///
+/// let snapshots = (0..8)
+///     .map(|height| Layer {
+///         height,
+///         image_name: format!("snapshot-{}", height),
+///         creation_command: format!("nightly build {}", height),
+///     })
+///     .collect();
+/// let bisection = bisect_sequence(snapshots, &Snapshot, &BisectOptions::default()).unwrap();
+/// assert_eq!(1, bisection.transitions.len());
+/// assert_eq!("snapshot-3", bisection.transitions[0].after.layer.image_name);
+/// ```
+pub fn bisect_sequence<T>(
+    items: Vec<Layer>,
+    action: &T,
+    options: &BisectOptions,
+) -> Result<Bisection, BisectError>
+where
+    T: ContainerAction + 'static,
+{
+    let layers = pin_layers(items, options.good.as_deref(), options.bad.as_deref())?;
+
     if layers.len() < 2 {
-        println!();
-        eprintln!(
-            "{} layers found in cache - not enough layers to bisect.",
-            layers.len()
-        );
-        return Err(Error::new(
-            std::io::ErrorKind::Other,
-            "no cached layers found!",
-        ));
+        return Err(BisectError::NotEnoughLayers(layers.len()));
     }
 
-    let results = get_changes(layers, &create_and_try_container);
-    create_and_try_container.pb.finish_with_message("done");
-    results
+    let compare = Comparison {
+        mode: options.compare,
+        streams: options.streams,
+        normalise: options.normalise.clone(),
+        classifier: options.classifier.clone(),
+    };
+    let flaky = Arc::new(Mutex::new(Vec::new()));
+    let (mode, jobs) = (options.mode, options.jobs);
+    let transitions = if options.repeat > 1 {
+        let repeated = RepeatAction {
+            inner: action.clone(),
+            times: options.repeat,
+            compare: compare.clone(),
+            flaky: flaky.clone(),
+        };
+        search(layers, &repeated, &compare, mode, jobs)
+    } else if let (Some(dir), Some(fingerprint)) = (&options.cache_dir, action.fingerprint()) {
+        let cached = CachedAction {
+            fingerprint,
+            inner: action.clone(),
+            dir: dir.clone(),
+        };
+        search(layers, &cached, &compare, mode, jobs)
+    } else {
+        search(layers, action, &compare, mode, jobs)
+    }?;
+
+    let mut flaky = flaky.lock().expect("flaky lock").clone();
+    flaky.sort();
+    Ok(Bisection { transitions, flaky })
+}
+
+/// Looks for transitions in the way the mode asks for.
+fn search<T>(
+    layers: Vec<Layer>,
+    action: &T,
+    compare: &Comparison,
+    mode: BisectMode,
+    jobs: usize,
+) -> Result<Vec<Transition>, BisectError>
+where
+    T: ContainerAction + 'static,
+{
+    match mode {
+        BisectMode::AllChanges => get_changes(layers, action, compare, jobs),
+        BisectMode::FirstBad => first_bad(layers, action, compare, jobs),
+        BisectMode::Exhaustive => {
+            let results = run_every_layer(&layers, action, compare, jobs)?;
+            let transitions = transitions_between(&results, compare);
+            if let Ok(transitions) = &transitions {
+                let missed = bisect_disagreements(&results, transitions, compare);
+                if !missed.is_empty() {
+                    eprintln!(
+                        "{} bisecting would not agree with the exhaustive run at layers {:?} \
+                         (the output changes and changes back).",
+                        "Warning:".yellow().bold(),
+                        missed
+                    );
+                }
+            }
+            transitions
+        }
+    }
 }
 
 #[cfg(test)]
@@ -386,28 +1459,41 @@ mod tests {
     #[derive(Clone)]
     struct MapAction {
         map: HashMap<String, String>,
+        exit_codes: HashMap<String, i32>,
     }
 
     impl MapAction {
         fn new(from: Vec<usize>, to: Vec<&str>) -> Self {
             let mut object = MapAction {
                 map: HashMap::new(),
+                exit_codes: HashMap::new(),
             };
             for (f, t) in from.iter().zip(to.iter()) {
                 object.map.insert(f.to_string(), t.to_string());
             }
             object
         }
+
+        fn with_exit_codes(mut self, from: Vec<usize>, codes: Vec<i32>) -> Self {
+            for (f, c) in from.iter().zip(codes.iter()) {
+                self.exit_codes.insert(f.to_string(), *c);
+            }
+            self
+        }
     }
 
     impl ContainerAction for MapAction {
-        fn try_container(&self, container_id: &str) -> String {
+        fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
             let none = String::new();
-            let result: &String = self.map.get(container_id).unwrap_or(&none);
-            result.clone()
+            let result: &String = self.map.get(&layer.image_name).unwrap_or(&none);
+            Ok(LayerResult::new(
+                layer.clone(),
+                result.clone(),
+                String::new(),
+                result.clone(),
+                self.exit_codes.get(&layer.image_name).cloned(),
+            ))
         }
-
-        fn skip(&self, _count: u64) -> () {}
     }
 
     fn lay(id: usize) -> Layer {
@@ -418,21 +1504,30 @@ mod tests {
         }
     }
 
+    fn out(id: usize, output: &str) -> LayerResult {
+        LayerResult::new(
+            lay(id),
+            output.to_string(),
+            String::new(),
+            output.to_string(),
+            None,
+        )
+    }
+
     #[test]
     fn if_output_always_same_return_earliest_command() {
         let results = get_changes(
             vec![lay(1), lay(2), lay(3)],
             &MapAction::new(vec![1, 2, 3], vec!["A", "A", "A"]),
+            &Comparison::default(),
+            2,
         );
 
         assert_eq!(
             results.unwrap(),
             vec![Transition {
                 before: None,
-                after: LayerResult {
-                    layer: lay(3),
-                    result: "A".to_string()
-                },
+                after: out(3, "A"),
             }]
         );
     }
@@ -442,19 +1537,15 @@ mod tests {
         let results = get_changes(
             vec![lay(1), lay(2), lay(3)],
             &MapAction::new(vec![1, 2, 3], vec!["A", "A", "B"]),
+            &Comparison::default(),
+            2,
         );
 
         assert_eq!(
             results.unwrap(),
             vec![Transition {
-                before: Some(LayerResult {
-                    layer: lay(2),
-                    result: "A".to_string()
-                }),
-                after: LayerResult {
-                    layer: lay(3),
-                    result: "B".to_string()
-                },
+                before: Some(out(2, "A")),
+                after: out(3, "B"),
             }]
         );
     }
@@ -464,6 +1555,8 @@ mod tests {
         let results = get_changes(
             vec![lay(1), lay(2), lay(3), lay(4)],
             &MapAction::new(vec![1, 2, 3, 4], vec!["A", "B", "B", "C"]),
+            &Comparison::default(),
+            2,
         );
 
         let res = results.unwrap();
@@ -472,24 +1565,12 @@ mod tests {
             res,
             vec![
                 Transition {
-                    before: Some(LayerResult {
-                        layer: lay(1),
-                        result: "A".to_string()
-                    }),
-                    after: LayerResult {
-                        layer: lay(2),
-                        result: "B".to_string()
-                    },
+                    before: Some(out(1, "A")),
+                    after: out(2, "B"),
                 },
                 Transition {
-                    before: Some(LayerResult {
-                        layer: lay(3),
-                        result: "B".to_string()
-                    }),
-                    after: LayerResult {
-                        layer: lay(4),
-                        result: "C".to_string()
-                    },
+                    before: Some(out(3, "B")),
+                    after: out(4, "C"),
                 }
             ]
         );
@@ -514,6 +1595,8 @@ mod tests {
                 vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10],
                 vec!["A", "B", "B", "C", "C", "C", "C", "C", "D", "D"],
             ),
+            &Comparison::default(),
+            2,
         );
         let res = results.unwrap();
 
@@ -521,36 +1604,428 @@ mod tests {
             res,
             vec![
                 Transition {
-                    before: Some(LayerResult {
-                        layer: lay(1),
-                        result: "A".to_string()
-                    }),
-                    after: LayerResult {
-                        layer: lay(2),
-                        result: "B".to_string()
-                    },
+                    before: Some(out(1, "A")),
+                    after: out(2, "B"),
                 },
                 Transition {
-                    before: Some(LayerResult {
-                        layer: lay(3),
-                        result: "B".to_string()
-                    }),
-                    after: LayerResult {
-                        layer: lay(4),
-                        result: "C".to_string()
-                    },
+                    before: Some(out(3, "B")),
+                    after: out(4, "C"),
                 },
                 Transition {
-                    before: Some(LayerResult {
-                        layer: lay(8),
-                        result: "C".to_string()
-                    }),
-                    after: LayerResult {
-                        layer: lay(9),
-                        result: "D".to_string()
-                    },
+                    before: Some(out(8, "C")),
+                    after: out(9, "D"),
                 }
             ]
         );
     }
+
+    #[test]
+    fn exit_code_mode_finds_transition_hidden_in_output() {
+        let action = MapAction::new(vec![1, 2, 3], vec!["A", "A", "A"])
+            .with_exit_codes(vec![1, 2, 3], vec![0, 0, 1]);
+
+        let by_output = get_changes(
+            vec![lay(1), lay(2), lay(3)],
+            &action,
+            &Comparison::default(),
+            2,
+        );
+        assert_eq!(by_output.unwrap().len(), 1);
+
+        let compare = Comparison {
+            mode: CompareMode::ExitCode,
+            ..Comparison::default()
+        };
+        let by_exit_code = get_changes(vec![lay(1), lay(2), lay(3)], &action, &compare, 2).unwrap();
+        assert_eq!(
+            by_exit_code,
+            vec![Transition {
+                before: Some(LayerResult {
+                    exit_code: Some(0),
+                    ..out(2, "A")
+                }),
+                after: LayerResult {
+                    exit_code: Some(1),
+                    ..out(3, "A")
+                },
+            }]
+        );
+    }
+
+    #[test]
+    fn demux_splits_framed_log_streams() {
+        let mut raw = vec![1, 0, 0, 0, 0, 0, 0, 3];
+        raw.extend_from_slice(b"out");
+        raw.extend_from_slice(&[2, 0, 0, 0, 0, 0, 0, 4]);
+        raw.extend_from_slice(b"warn");
+        raw.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 9]);
+        raw.extend_from_slice(b"trunc"); // cut short by the timeout
+
+        let (stdout, stderr, combined) = demux_log(&raw);
+        assert_eq!(b"outtrunc".to_vec(), stdout);
+        assert_eq!(b"warn".to_vec(), stderr);
+        assert_eq!(b"outwarntrunc".to_vec(), combined);
+    }
+
+    #[test]
+    fn demux_treats_unframed_output_as_stdout() {
+        let (stdout, stderr, _) = demux_log(b"hello world");
+        assert_eq!(b"hello world".to_vec(), stdout);
+        assert!(stderr.is_empty());
+    }
+
+    #[test]
+    fn normalised_output_hides_noise() {
+        let action = MapAction::new(
+            vec![1, 2, 3],
+            vec!["at 10:01 ok", "at 10:02 ok", "at 10:03 ok"],
+        );
+        let compare = Comparison {
+            normalise: Normaliser {
+                rules: vec![Rule::Replace(
+                    regex::Regex::new(r"\d\d:\d\d").unwrap(),
+                    "TIME".into(),
+                )],
+            },
+            ..Comparison::default()
+        };
+
+        let results = get_changes(vec![lay(1), lay(2), lay(3)], &action, &compare, 2).unwrap();
+        assert_eq!(1, results.len());
+        assert_eq!(None, results[0].before);
+    }
+
+    /// Bad once the output mentions "broken", can't tell when it says "???".
+    #[derive(Debug)]
+    struct BrokenClassifier;
+
+    impl Classifier for BrokenClassifier {
+        fn classify(&self, result: &LayerResult) -> Result<Verdict, ActionError> {
+            Ok(match result.result.as_str() {
+                "???" => Verdict::Skip,
+                r if r.contains("broken") => Verdict::Bad,
+                _ => Verdict::Good,
+            })
+        }
+    }
+
+    #[test]
+    fn classifier_verdicts_replace_output_comparison() {
+        let action = MapAction::new(
+            vec![1, 2, 3, 4, 5, 6],
+            vec!["ok v1", "ok v2", "???", "???", "broken v3", "broken v4"],
+        );
+        let compare = Comparison {
+            classifier: Some(Arc::new(BrokenClassifier)),
+            ..Comparison::default()
+        };
+
+        let results = get_changes(
+            vec![lay(1), lay(2), lay(3), lay(4), lay(5), lay(6)],
+            &action,
+            &compare,
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(
+            results,
+            vec![Transition {
+                before: Some(LayerResult {
+                    verdict: Some(Verdict::Good),
+                    ..out(2, "ok v2")
+                }),
+                after: LayerResult {
+                    verdict: Some(Verdict::Bad),
+                    ..out(5, "broken v3")
+                },
+            }]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn script_classifier_reads_output_and_exit_code() {
+        let script = ScriptClassifier {
+            script: "grep -q 'is broken' && exit 1; exit $DOCKER_BISECT_EXIT_CODE".into(),
+        };
+        let ok = LayerResult {
+            exit_code: Some(0),
+            ..out(1, "fine")
+        };
+        assert_eq!(Verdict::Good, script.classify(&ok).unwrap());
+        assert_eq!(
+            Verdict::Bad,
+            script.classify(&out(2, "it is broken")).unwrap()
+        );
+        let skipped = LayerResult {
+            exit_code: Some(125),
+            ..out(3, "fine")
+        };
+        assert_eq!(Verdict::Skip, script.classify(&skipped).unwrap());
+
+        let missing = ScriptClassifier {
+            script: "/nonexistent/classify.sh".into(),
+        };
+        assert!(missing.classify(&ok).is_err());
+    }
+
+    #[test]
+    fn first_bad_finds_only_the_first_regression() {
+        let results = first_bad(
+            (1..=8).map(lay).collect(),
+            &MapAction::new(
+                (1..=8).collect(),
+                vec!["A", "A", "A", "B", "B", "C", "C", "C"],
+            ),
+            &Comparison::default(),
+            2,
+        );
+
+        assert_eq!(
+            results.unwrap(),
+            vec![Transition {
+                before: Some(out(3, "A")),
+                after: out(4, "B"),
+            }]
+        );
+    }
+
+    #[test]
+    fn pinned_endpoints_by_height_or_id() {
+        let layers = vec![
+            Layer {
+                image_name: "sha256:4aa111".into(),
+                ..lay(0)
+            },
+            Layer {
+                image_name: "sha256:bbb222".into(),
+                ..lay(3)
+            },
+            Layer {
+                image_name: "sha256:bbc333".into(),
+                ..lay(4)
+            },
+        ];
+
+        // "4" is the height of the last layer, not the id prefix of the first.
+        let pinned = pin_layers(layers.clone(), Some("3"), Some("4")).unwrap();
+        assert_eq!(vec![layers[1].clone(), layers[2].clone()], pinned);
+        let pinned = pin_layers(layers.clone(), Some("4aa"), Some("bbc")).unwrap();
+        assert_eq!(layers, pinned);
+        assert!(matches!(
+            pin_layers(layers.clone(), Some("4"), Some("bbb")),
+            Err(BisectError::GoodAfterBad)
+        ));
+        assert!(matches!(
+            pin_layers(layers.clone(), Some("bb"), None),
+            Err(BisectError::AmbiguousLayer(_))
+        ));
+        assert!(matches!(
+            pin_layers(layers.clone(), Some("zzz"), None),
+            Err(BisectError::LayerNotFound(_))
+        ));
+
+        let action = MapAction::new(vec![0, 3, 4], vec!["A", "A", "B"]);
+        let options = BisectOptions {
+            good: Some("4".into()),
+            ..BisectOptions::default()
+        };
+        assert!(matches!(
+            bisect_sequence(layers, &action, &options),
+            Err(BisectError::NotEnoughLayers(1))
+        ));
+    }
+
+    #[test]
+    fn exhaustive_finds_flip_flops_that_bisect_misses() {
+        let layers: Vec<Layer> = (1..=5).map(lay).collect();
+        let action = MapAction::new((1..=5).collect(), vec!["A", "A", "B", "A", "A"]);
+        let compare = Comparison::default();
+
+        let bisected = get_changes(layers.clone(), &action, &compare, 2).unwrap();
+        assert_eq!(None, bisected[0].before);
+
+        let results = run_every_layer(&layers, &action, &compare, 2).unwrap();
+        assert_eq!(
+            layers,
+            results.iter().map(|r| r.layer.clone()).collect::<Vec<_>>()
+        );
+        let transitions = transitions_between(&results, &compare).unwrap();
+        assert_eq!(
+            transitions,
+            vec![
+                Transition {
+                    before: Some(out(2, "A")),
+                    after: out(3, "B"),
+                },
+                Transition {
+                    before: Some(out(3, "B")),
+                    after: out(4, "A"),
+                },
+            ]
+        );
+        assert_eq!(
+            vec![3, 4],
+            bisect_disagreements(&results, &transitions, &compare)
+        );
+    }
+
+    #[test]
+    fn exhaustive_agrees_with_bisect_on_monotonic_changes() {
+        let layers: Vec<Layer> = (1..=4).map(lay).collect();
+        let action = MapAction::new((1..=4).collect(), vec!["A", "B", "B", "C"]);
+        let compare = Comparison::default();
+
+        let results = run_every_layer(&layers, &action, &compare, 3).unwrap();
+        let transitions = transitions_between(&results, &compare).unwrap();
+        assert_eq!(
+            transitions,
+            get_changes(layers, &action, &compare, 2).unwrap()
+        );
+        assert!(bisect_disagreements(&results, &transitions, &compare).is_empty());
+    }
+
+    /// Records how many containers are running at the same time.
+    #[derive(Clone)]
+    struct BusyAction {
+        running: Arc<std::sync::atomic::AtomicUsize>,
+        most: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl ContainerAction for BusyAction {
+        fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+            use std::sync::atomic::Ordering::SeqCst;
+            let now = self.running.fetch_add(1, SeqCst) + 1;
+            self.most.fetch_max(now, SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            self.running.fetch_sub(1, SeqCst);
+            Ok(out(layer.height, &layer.height.to_string()))
+        }
+    }
+
+    #[test]
+    fn pool_limits_running_containers_and_keeps_order() {
+        let action = BusyAction {
+            running: Arc::default(),
+            most: Arc::default(),
+        };
+        let layers: Vec<Layer> = (1..=12).map(lay).collect();
+
+        let results = run_every_layer(&layers, &action, &Comparison::default(), 3).unwrap();
+
+        let order: Vec<usize> = results.iter().map(|r| r.layer.height).collect();
+        assert_eq!((1..=12).collect::<Vec<_>>(), order);
+        assert!(action.most.load(std::sync::atomic::Ordering::SeqCst) <= 3);
+    }
+
+    /// Gives a different answer every time on layers listed as flaky.
+    #[derive(Clone)]
+    struct FlakyAction {
+        map: MapAction,
+        flaky: Vec<usize>,
+        calls: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl ContainerAction for FlakyAction {
+        fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+            let call = self.calls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let mut result = self.map.try_container(layer)?;
+            if self.flaky.contains(&layer.height) {
+                result.stdout = format!("random {}", call);
+            }
+            Ok(result)
+        }
+    }
+
+    #[test]
+    fn flaky_layers_are_skipped_instead_of_reported() {
+        let action = FlakyAction {
+            map: MapAction::new((1..=5).collect(), vec!["A", "A", "A", "B", "B"]),
+            flaky: vec![3],
+            calls: Arc::default(),
+        };
+        let options = BisectOptions {
+            repeat: 3,
+            ..BisectOptions::default()
+        };
+
+        let bisection = bisect_sequence((1..=5).map(lay).collect(), &action, &options).unwrap();
+
+        assert_eq!(
+            bisection.transitions,
+            vec![Transition {
+                before: Some(out(2, "A")),
+                after: out(4, "B"),
+            }]
+        );
+        assert_eq!(vec![lay(3)], bisection.flaky);
+    }
+
+    /// Can't produce a result for the `missing` layers, and loses the daemon on the
+    /// `unreachable` ones.
+    #[derive(Clone)]
+    struct MissingSnapshots {
+        map: MapAction,
+        missing: Vec<usize>,
+        unreachable: Vec<usize>,
+    }
+
+    impl ContainerAction for MissingSnapshots {
+        fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+            if self.missing.contains(&layer.height) {
+                return Err(format!("snapshot {} was deleted", layer.height).into());
+            }
+            if self.unreachable.contains(&layer.height) {
+                return Err(Box::new(BisectError::DaemonUnreachable {
+                    host: "unix:///var/run/docker.sock".into(),
+                    message: "connection refused".into(),
+                }));
+            }
+            self.map.try_container(layer)
+        }
+    }
+
+    #[test]
+    fn failed_layers_are_skipped() {
+        let mut action = MissingSnapshots {
+            map: MapAction::new((1..=6).collect(), vec!["A", "A", "A", "B", "B", "B"]),
+            missing: vec![3],
+            unreachable: vec![],
+        };
+        let layers: Vec<Layer> = (1..=6).map(lay).collect();
+
+        let bisection =
+            bisect_sequence(layers.clone(), &action, &BisectOptions::default()).unwrap();
+        assert_eq!(
+            bisection.transitions,
+            vec![Transition {
+                before: Some(out(2, "A")),
+                after: out(4, "B"),
+            }]
+        );
+
+        action.missing = (1..=6).collect();
+        for mode in &[
+            BisectMode::AllChanges,
+            BisectMode::FirstBad,
+            BisectMode::Exhaustive,
+        ] {
+            let options = BisectOptions {
+                mode: *mode,
+                ..BisectOptions::default()
+            };
+            match bisect_sequence(layers.clone(), &action, &options).unwrap_err() {
+                BisectError::AllSkipped => {}
+                other => panic!("unexpected error {:?}", other),
+            }
+        }
+
+        action.missing = vec![3];
+        action.unreachable = vec![4];
+        match bisect_sequence(layers, &action, &BisectOptions::default()).unwrap_err() {
+            BisectError::DaemonUnreachable { .. } => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
 }
@@ -0,0 +1,186 @@
+//! Network isolation and resource limits for the containers, so that results don't
+//! depend on what the host can reach and parallel runs don't fight over the host.
+use std::fmt;
+use std::str::FromStr;
+
+use dockworker::ContainerHostConfig;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// CFS period that `cpus` is a share of, as used by `docker run --cpus`.
+const CPU_PERIOD: u64 = 100_000;
+
+/// The largest `pids_limit` that can be passed on. Docker's `PidsLimit` is an int64, but
+/// dockworker 0.0.8's `ContainerHostConfig::pids_limit` takes an i16.
+pub const MAX_PIDS_LIMIT: u32 = i16::MAX as u32;
+
+/// Which network the containers are on.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Network {
+    /// Whatever the runtime does: docker's bridge, an empty network namespace with an
+    /// OCI runtime, the host's network with chroot.
+    #[default]
+    Default,
+    /// Only a loopback interface.
+    None,
+    /// Docker's bridge network (docker only).
+    Bridge,
+    /// The host's network.
+    Host,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "default" => Ok(Network::Default),
+            "none" => Ok(Network::None),
+            "bridge" => Ok(Network::Bridge),
+            "host" => Ok(Network::Host),
+            _ => Err(format!(
+                "{} is not a network (expected none, bridge or host)",
+                text
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Network::Default => "default",
+            Network::None => "none",
+            Network::Bridge => "bridge",
+            Network::Host => "host",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Network and resources for each container. `None` is unlimited.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Limits {
+    pub network: Network,
+    /// Memory limit in bytes.
+    pub memory: Option<u64>,
+    /// How many CPUs' worth of time the container may use, e.g. 1.5.
+    pub cpus: Option<f64>,
+    /// Maximum number of processes, up to `MAX_PIDS_LIMIT`.
+    pub pids_limit: Option<u32>,
+}
+
+impl Limits {
+    /// The limits as docker-bisect options, so that a run can be repeated with them.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.network != Network::Default {
+            args.push(format!("--network={}", self.network));
+        }
+        if let Some(memory) = self.memory {
+            args.push(format!("--memory={}", memory));
+        }
+        if let Some(cpus) = self.cpus {
+            args.push(format!("--cpus={}", cpus));
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            args.push(format!("--pids-limit={}", pids_limit));
+        }
+        args
+    }
+
+    /// Whether any resource (rather than the network) is limited.
+    #[cfg(unix)]
+    pub(crate) fn has_resources(&self) -> bool {
+        self.memory.is_some() || self.cpus.is_some() || self.pids_limit.is_some()
+    }
+
+    /// CFS quota and period for `cpus`.
+    pub(crate) fn cpu_quota(&self) -> Option<(u64, u64)> {
+        self.cpus
+            .map(|cpus| ((cpus * CPU_PERIOD as f64).round() as u64, CPU_PERIOD))
+    }
+
+    /// A key for the limits in cache fingerprints (`cpus` isn't hashable).
+    pub(crate) fn key(&self) -> (Network, Option<u64>, Option<u64>, Option<u32>) {
+        (
+            self.network,
+            self.memory,
+            self.cpus.map(f64::to_bits),
+            self.pids_limit,
+        )
+    }
+
+    pub(crate) fn apply(&self, host_config: &mut ContainerHostConfig) {
+        if self.network != Network::Default {
+            host_config.network_mode(self.network.to_string());
+        }
+        if let Some(memory) = self.memory {
+            host_config.memory(memory);
+        }
+        if let Some((quota, period)) = self.cpu_quota() {
+            host_config.cpu_quota(quota);
+            host_config.cpu_period(period);
+        }
+        if let Some(pids_limit) = self.pids_limit {
+            host_config.pids_limit(pids_limit.min(MAX_PIDS_LIMIT) as i16);
+        }
+    }
+}
+
+/// Parses a memory size such as `512m` or `2g` (binary units b, k, m or g, as docker
+/// takes them). A bare number is bytes.
+///
+/// # Example
+/// ```
+/// use docker_bisect::parse_memory;
+/// assert_eq!(Ok(512 * 1024 * 1024), parse_memory("512m"));
+/// assert_eq!(Ok(1000), parse_memory("1000"));
+/// assert!(parse_memory("lots").is_err());
+/// ```
+pub fn parse_memory(text: &str) -> Result<u64, String> {
+    let invalid = || format!("{} is not a memory size (expected e.g. 512m or 2g)", text);
+    let lower = text.to_ascii_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let number: u64 = number.parse().map_err(|_| invalid())?;
+    let unit = match unit.strip_suffix('b').unwrap_or(unit) {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    number.checked_mul(unit).ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_recorded_as_options() {
+        let limits = Limits {
+            network: "none".parse().unwrap(),
+            memory: Some(parse_memory("64M").unwrap()),
+            cpus: Some(1.5),
+            pids_limit: Some(100),
+        };
+        assert_eq!(
+            vec![
+                "--network=none",
+                "--memory=67108864",
+                "--cpus=1.5",
+                "--pids-limit=100"
+            ],
+            limits.args()
+        );
+        assert_eq!(Some((150_000, 100_000)), limits.cpu_quota());
+        assert!(Limits::default().args().is_empty());
+        assert!("wifi".parse::<Network>().is_err());
+    }
+}
@@ -0,0 +1,146 @@
+//! Host files and directories made available in every container: bind mounts, which
+//! the command sees as they are on the host, and copies, put into each container before
+//! it starts.
+use std::io::{Error, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// A host file or directory bind-mounted into every container (`src:dst[:ro]`).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Mount {
+    /// Absolute path on the host (docker takes anything else for a volume name).
+    pub source: PathBuf,
+    /// Path in the container.
+    pub target: String,
+    pub read_only: bool,
+}
+
+impl Mount {
+    /// The mount as a docker bind, `src:dst[:ro]`.
+    pub(crate) fn bind(&self) -> String {
+        let mode = if self.read_only { ":ro" } else { "" };
+        format!("{}:{}{}", self.source.display(), self.target, mode)
+    }
+}
+
+impl FromStr for Mount {
+    type Err = String;
+
+    /// Parses `src:dst`, `src:dst:ro` or `src:dst:rw`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = text.split(':').collect();
+        let read_only = match parts.get(2) {
+            None => false,
+            Some(&"ro") => true,
+            Some(&"rw") => false,
+            Some(mode) => return Err(format!("{} is not a mount mode (expected ro or rw)", mode)),
+        };
+        match parts[..] {
+            [source, target, ..] if parts.len() <= 3 && !source.is_empty() => Ok(Mount {
+                source: source.into(),
+                target: container_path(target)?,
+                read_only,
+            }),
+            _ => Err(format!("{} is not a mount (expected src:dst[:ro])", text)),
+        }
+    }
+}
+
+/// A host file or directory copied into every container before it starts (`src:dst`).
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct FileCopy {
+    pub source: PathBuf,
+    /// Path in the container. Files already there are overwritten.
+    pub target: String,
+}
+
+impl FromStr for FileCopy {
+    type Err = String;
+
+    /// Parses `src:dst`.
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.split(':').collect::<Vec<_>>()[..] {
+            [source, target] if !source.is_empty() => Ok(FileCopy {
+                source: source.into(),
+                target: container_path(target)?,
+            }),
+            _ => Err(format!("{} is not a copy (expected src:dst)", text)),
+        }
+    }
+}
+
+fn container_path(path: &str) -> Result<String, String> {
+    if path.starts_with('/') && path != "/" {
+        Ok(path.to_string())
+    } else {
+        Err(format!("{} is not an absolute path in the container", path))
+    }
+}
+
+/// Writes the copies as one tar archive, with every target relative to `/`.
+pub(crate) fn write_copies<W: Write>(copies: &[FileCopy], out: W) -> Result<W, Error> {
+    let mut tar = tar::Builder::new(out);
+    for copy in copies {
+        let name = Path::new(copy.target.trim_start_matches('/'));
+        if copy.source.is_dir() {
+            tar.append_dir_all(name, &copy.source)?;
+        } else {
+            tar.append_path_with_name(&copy.source, name)?;
+        }
+    }
+    tar.into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn mounts_and_copies_parse_from_the_command_line() {
+        let mount: Mount = "/host/fixtures:/fixtures:ro".parse().unwrap();
+        assert_eq!("/host/fixtures:/fixtures:ro", mount.bind());
+        let mount: Mount = "/host/out:/out".parse().unwrap();
+        assert!(!mount.read_only);
+        assert!("/host:/in:rx".parse::<Mount>().is_err());
+        assert!("/host:relative".parse::<Mount>().is_err());
+        assert!("/host".parse::<Mount>().is_err());
+
+        let copy: FileCopy = "test.sh:/usr/bin/test.sh".parse().unwrap();
+        assert_eq!(PathBuf::from("test.sh"), copy.source);
+        assert!("test.sh:/bin:ro".parse::<FileCopy>().is_err());
+    }
+
+    #[test]
+    fn copies_are_archived_at_their_targets() {
+        let dir = env::temp_dir().join(format!("docker-bisect-copies-{}", std::process::id()));
+        fs::create_dir_all(dir.join("fixtures")).unwrap();
+        fs::write(dir.join("test.sh"), "echo ok").unwrap();
+        fs::write(dir.join("fixtures/data"), "1").unwrap();
+        let copies = vec![
+            FileCopy {
+                source: dir.join("test.sh"),
+                target: "/usr/bin/probe".into(),
+            },
+            FileCopy {
+                source: dir.join("fixtures"),
+                target: "/srv/fixtures".into(),
+            },
+        ];
+
+        let archive = write_copies(&copies, Vec::new()).unwrap();
+        let _ = fs::remove_dir_all(&dir);
+        let mut names: Vec<String> = tar::Archive::new(&archive[..])
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        names.sort();
+
+        assert_eq!(
+            vec!["srv/fixtures/", "srv/fixtures/data", "usr/bin/probe"],
+            names
+        );
+    }
+}
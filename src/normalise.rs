@@ -0,0 +1,105 @@
+//! Rules for cleaning up command output before layers are compared, so that
+//! timestamps, PIDs, temp paths and hostnames don't show up as transitions.
+use regex::Regex;
+
+/// A single rewrite step applied to command output.
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Replace every match of the regex (`$1` style capture references are allowed).
+    Replace(Regex, String),
+    /// Remove whitespace at the end of each line and trailing blank lines.
+    TrimTrailingWhitespace,
+    /// Sort the lines so that ordering differences are ignored.
+    SortLines,
+    /// Drop every line that matches the regex.
+    DropLines(Regex),
+}
+
+impl Rule {
+    /// Removes ANSI colour and cursor escape sequences.
+    pub fn strip_ansi() -> Rule {
+        let ansi = Regex::new(r"\x1b\[[0-9;?]*[ -/]*[@-~]").expect("valid ansi regex");
+        Rule::Replace(ansi, String::new())
+    }
+}
+
+/// An ordered list of rules. The default normaliser leaves output untouched.
+#[derive(Debug, Clone, Default)]
+pub struct Normaliser {
+    pub rules: Vec<Rule>,
+}
+
+impl Normaliser {
+    /// Runs every rule in order over the output.
+    ///
+    /// # Example
+    /// ```
+    /// use docker_bisect::{Normaliser, Rule};
+    /// use regex::Regex;
+    /// let normaliser = Normaliser {
+    ///     rules: vec![
+    ///         Rule::Replace(Regex::new(r"pid \d+").unwrap(), "pid N".into()),
+    ///         Rule::SortLines,
+    ///     ],
+    /// };
+    /// assert_eq!("a\nstarted pid N", normaliser.apply("started pid 42\na"));
+    /// ```
+    pub fn apply(&self, output: &str) -> String {
+        let mut output = output.to_string();
+        for rule in &self.rules {
+            output = match rule {
+                Rule::Replace(regex, with) => regex.replace_all(&output, with.as_str()).to_string(),
+                Rule::TrimTrailingWhitespace => {
+                    let lines: Vec<&str> = output.lines().map(str::trim_end).collect();
+                    lines.join("\n").trim_end().to_string()
+                }
+                Rule::SortLines => {
+                    let mut lines: Vec<&str> = output.lines().collect();
+                    lines.sort_unstable();
+                    lines.join("\n")
+                }
+                Rule::DropLines(regex) => {
+                    let lines: Vec<&str> = output.lines().filter(|l| !regex.is_match(l)).collect();
+                    lines.join("\n")
+                }
+            };
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_ansi_and_trim() {
+        let normaliser = Normaliser {
+            rules: vec![Rule::strip_ansi(), Rule::TrimTrailingWhitespace],
+        };
+        assert_eq!(
+            "ok\nwarning",
+            normaliser.apply("\x1b[32mok\x1b[0m  \n\x1b[1;33mwarning\x1b[0m\n\n")
+        );
+    }
+
+    #[test]
+    fn drop_lines_matching_pattern() {
+        let normaliser = Normaliser {
+            rules: vec![Rule::DropLines(Regex::new("^DEPRECATED").unwrap())],
+        };
+        assert_eq!("a\nb", normaliser.apply("a\nDEPRECATED: old\nb"));
+    }
+
+    #[test]
+    fn rules_apply_in_order() {
+        let hostname = Regex::new("[0-9a-f]{12}").unwrap();
+        let normaliser = Normaliser {
+            rules: vec![
+                Rule::Replace(hostname.clone(), "HOST".into()),
+                Rule::DropLines(hostname),
+            ],
+        };
+        assert_eq!("HOST up", normaliser.apply("0123456789ab up"));
+    }
+}
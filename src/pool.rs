@@ -0,0 +1,134 @@
+//! A fixed number of worker threads that run the command on layers, so that
+//! bisecting a big image doesn't flood the docker daemon with containers.
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use super::{ActionError, BisectError, Comparison, ContainerAction, Layer, LayerResult, Verdict};
+
+pub(crate) struct Pool<T> {
+    action: T,
+    jobs: Option<Sender<(usize, Layer)>>,
+    results: Receiver<(usize, thread::Result<Result<LayerResult, ActionError>>)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T> Pool<T>
+where
+    T: ContainerAction + 'static,
+{
+    /// Starts `size` workers (at least one). Each worker judges its results with `compare`.
+    pub(crate) fn new(action: &T, compare: &Comparison, size: usize) -> Pool<T> {
+        let (job_sender, job_receiver) = channel::<(usize, Layer)>();
+        let (result_sender, results) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..size.max(1))
+            .map(|_| {
+                let jobs = job_receiver.clone();
+                let result_sender = result_sender.clone();
+                let (action, compare) = (action.clone(), compare.clone());
+                thread::spawn(move || loop {
+                    let job = jobs.lock().expect("job queue lock").recv();
+                    let (index, layer) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    // A panic is handed back to `run` so it isn't left waiting forever.
+                    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                        action.try_container(&layer).and_then(|r| compare.judge(r))
+                    }));
+                    if result_sender.send((index, result)).is_err() {
+                        break;
+                    }
+                })
+            })
+            .collect();
+
+        Pool {
+            action: action.clone(),
+            jobs: Some(job_sender),
+            results,
+            workers,
+        }
+    }
+
+    /// Runs the layers, handing them to workers in the order given.
+    /// Results come back in the same order as the layers. A layer the action fails on is
+    /// skipped, unless the failure isn't specific to the layer (such as the daemon going
+    /// away): then the first such failure is returned once every layer has finished.
+    pub(crate) fn run(&self, layers: &[Layer]) -> Result<Vec<LayerResult>, BisectError> {
+        let jobs = self.jobs.as_ref().expect("pool is running");
+        for (index, layer) in layers.iter().enumerate() {
+            jobs.send((index, layer.clone()))
+                .expect("pool workers alive");
+        }
+        let mut results: Vec<Option<LayerResult>> = vec![None; layers.len()];
+        let mut failure = None;
+        for _ in layers {
+            let (index, result) = self.results.recv().expect("pool workers alive");
+            match result {
+                Ok(Ok(result)) => results[index] = Some(result),
+                Ok(Err(err)) => match skip_failed(&layers[index], err) {
+                    Ok(skipped) => results[index] = Some(skipped),
+                    Err(err) => {
+                        failure.get_or_insert(err);
+                    }
+                },
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+        match failure {
+            Some(err) => Err(err),
+            None => Ok(results.into_iter().flatten().collect()),
+        }
+    }
+
+    /// Runs a single layer.
+    pub(crate) fn run_one(&self, layer: &Layer) -> Result<LayerResult, BisectError> {
+        Ok(self
+            .run(std::slice::from_ref(layer))?
+            .pop()
+            .expect("one result"))
+    }
+
+    /// Records layers that didn't need to be run (for progress reporting).
+    pub(crate) fn skip(&self, count: u64) {
+        self.action.skip(count);
+    }
+}
+
+/// A skipped result for a layer that couldn't be run, or the error if it wasn't the
+/// layer's fault.
+fn skip_failed(layer: &Layer, err: ActionError) -> Result<LayerResult, BisectError> {
+    let err = match err.downcast::<BisectError>() {
+        Ok(err) => *err,
+        Err(source) => BisectError::Action {
+            layer: layer.clone(),
+            source,
+        },
+    };
+    match err {
+        BisectError::ContainerCreateFailed { .. }
+        | BisectError::Timeout { .. }
+        | BisectError::Action { .. } => {
+            let message = err.to_string();
+            Ok(LayerResult {
+                verdict: Some(Verdict::Skip),
+                ..LayerResult::new(layer.clone(), String::new(), message.clone(), message, None)
+            })
+        }
+        err => Err(err),
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        // Closing the job queue lets every worker finish.
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
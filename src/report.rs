@@ -0,0 +1,397 @@
+//! Reports of a bisect run for other tools (JSON, JUnit) and for people (HTML).
+use std::collections::BTreeMap;
+
+use dockworker::ImageLayer;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{diff, Bisection, Layer, LayerResult, Limits, Transition};
+
+/// A history entry without an image id, which can't be run and so was skipped.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct MissingLayer {
+    pub height: usize,
+    pub creation_command: String,
+}
+
+/// Missing layers in the same bottom-up order (and with the same heights) as `try_bisect` uses.
+pub fn missing_layers(histories: &[ImageLayer]) -> Vec<MissingLayer> {
+    histories
+        .iter()
+        .rev()
+        .enumerate()
+        .filter(|(_, event)| event.id.is_none())
+        .map(|(height, event)| MissingLayer {
+            height,
+            creation_command: event.created_by.clone(),
+        })
+        .collect()
+}
+
+/// One entry of the image history, bottom-up. Entries without an id are missing layers.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HistoryEntry {
+    pub height: usize,
+    pub id: Option<String>,
+    pub creation_command: String,
+}
+
+/// Everything a run found.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Report {
+    pub image: String,
+    pub command_line: Vec<String>,
+    pub transitions: Vec<Transition>,
+    pub missing_layers: Vec<MissingLayer>,
+    pub history: Vec<HistoryEntry>,
+    /// Layers that were skipped because repeated runs on them disagreed.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub flaky: Vec<Layer>,
+    /// The network and resource limits the containers ran with.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub limits: Limits,
+}
+
+impl Report {
+    pub fn new(
+        image: &str,
+        command_line: &[String],
+        histories: &[ImageLayer],
+        bisection: Bisection,
+        limits: &Limits,
+    ) -> Report {
+        Report {
+            image: image.to_string(),
+            command_line: command_line.to_vec(),
+            transitions: bisection.transitions,
+            missing_layers: missing_layers(histories),
+            history: histories
+                .iter()
+                .rev()
+                .enumerate()
+                .map(|(height, event)| HistoryEntry {
+                    height,
+                    id: event.id.clone(),
+                    creation_command: event.created_by.clone(),
+                })
+                .collect(),
+            flaky: bisection.flaky,
+            limits: limits.clone(),
+        }
+    }
+
+    fn is_flaky(&self, height: usize) -> bool {
+        self.flaky.iter().any(|layer| layer.height == height)
+    }
+
+    /// The report as pretty printed JSON.
+    #[cfg(feature = "json")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("report is serialisable")
+    }
+
+    /// The report as JUnit XML with one testcase per layer that could be run (every
+    /// history step with an id), so CI can show the culprit layers. Layers that changed
+    /// the output fail with the layer's creation command and the output diff, flaky
+    /// layers are skipped and the others pass.
+    pub fn to_junit(&self) -> String {
+        let changed: BTreeMap<usize, (&LayerResult, &LayerResult)> = self
+            .transitions
+            .iter()
+            .filter_map(|t| Some((t.after.layer.height, (t.before.as_ref()?, &t.after))))
+            .collect();
+        let cases: Vec<&HistoryEntry> = self.history.iter().filter(|e| e.id.is_some()).collect();
+        let failures = cases
+            .iter()
+            .filter(|e| changed.contains_key(&e.height))
+            .count();
+
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml += &format!(
+            "<testsuites>\n  <testsuite name=\"docker-bisect {}\" tests=\"{}\" failures=\"{}\" \
+             skipped=\"{}\">\n",
+            xml_escape(&self.image),
+            cases.len(),
+            failures,
+            cases.iter().filter(|e| self.is_flaky(e.height)).count()
+        );
+        xml += &format!(
+            "    <properties>\n      <property name=\"command\" value=\"{}\"/>\n",
+            xml_escape(&self.command_line.join(" "))
+        );
+        xml += &format!(
+            "      <property name=\"limits\" value=\"{}\"/>\n    </properties>\n",
+            xml_escape(&self.limits.args().join(" "))
+        );
+        for entry in cases {
+            let name = format!("{}: {}", entry.height, entry.creation_command);
+            xml += &format!(
+                "    <testcase classname=\"{}\" name=\"{}\"",
+                xml_escape(&self.image),
+                xml_escape(&name)
+            );
+            match changed.get(&entry.height) {
+                None if self.is_flaky(entry.height) => {
+                    xml +=
+                        ">\n      <skipped message=\"repeated runs disagreed\"/>\n    </testcase>\n"
+                }
+                None => xml += "/>\n",
+                Some((before, result)) => {
+                    xml += &format!(
+                        ">\n      <failure message=\"{}\">{}</failure>\n    </testcase>\n",
+                        xml_escape(&format!(
+                            "{} changed the output",
+                            result.layer.creation_command
+                        )),
+                        xml_escape(&format!(
+                            "{}\n\n{}",
+                            result.layer.creation_command,
+                            diff::unified(&before.result, &result.result, JUNIT_DIFF_CONTEXT)
+                        ))
+                    );
+                }
+            }
+        }
+        xml += "  </testsuite>\n</testsuites>\n";
+        xml
+    }
+
+    /// The report as a single static HTML page: the layer timeline with the transitions
+    /// highlighted, followed by a side-by-side output diff for each transition.
+    pub fn to_html(&self) -> String {
+        let changed: BTreeMap<usize, &Transition> = self
+            .transitions
+            .iter()
+            .filter(|t| t.before.is_some())
+            .map(|t| (t.after.layer.height, t))
+            .collect();
+
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>docker-bisect {image}</title>\n<style>{style}</style>\n</head>\n<body>\n\
+             <h1>docker-bisect {image}</h1>\n<p>Command: <code>{command}</code></p>\n",
+            image = xml_escape(&self.image),
+            style = HTML_STYLE,
+            command = xml_escape(&self.command_line.join(" "))
+        );
+        let limits = self.limits.args();
+        if !limits.is_empty() {
+            html += &format!(
+                "<p>Limits: <code>{}</code></p>\n",
+                xml_escape(&limits.join(" "))
+            );
+        }
+
+        html += "<h2>Layers</h2>\n<ol class=\"timeline\" start=\"0\">\n";
+        for entry in &self.history {
+            let command = xml_escape(&entry.creation_command);
+            if changed.contains_key(&entry.height) {
+                html += &format!(
+                    "<li class=\"changed\"><a href=\"#layer-{}\"><code>{}</code></a></li>\n",
+                    entry.height, command
+                );
+            } else if self.is_flaky(entry.height) {
+                html += &format!(
+                    "<li class=\"flaky\" title=\"runs disagreed, skipped\"><code>{}</code></li>\n",
+                    command
+                );
+            } else if entry.id.is_none() {
+                html += &format!(
+                    "<li class=\"missing\" title=\"no image id, skipped\"><code>{}</code></li>\n",
+                    command
+                );
+            } else {
+                html += &format!("<li><code>{}</code></li>\n", command);
+            }
+        }
+        html += "</ol>\n";
+
+        html += "<h2>Transitions</h2>\n";
+        if changed.is_empty() {
+            html += "<p>No layer changed the output.</p>\n";
+        }
+        for (height, transition) in &changed {
+            let before = transition
+                .before
+                .as_ref()
+                .expect("changed transitions have a before");
+            html += &format!(
+                "<h3 id=\"layer-{}\">{}: <code>{}</code></h3>\n<table class=\"diff\">\n\
+                 <tr><th>layer {}</th><th>layer {}</th></tr>\n",
+                height,
+                height,
+                xml_escape(&transition.after.layer.creation_command),
+                before.layer.height,
+                height
+            );
+            for (left, right) in diff::side_by_side(&before.result, &transition.after.result) {
+                html += &format!("<tr>{}{}</tr>\n", diff_cell(left), diff_cell(right));
+            }
+            html += "</table>\n";
+        }
+        html += "</body>\n</html>\n";
+        html
+    }
+}
+
+const HTML_STYLE: &str = "body{font-family:sans-serif;margin:2em}\
+    .timeline li.changed{background:#fdd;font-weight:bold}\
+    .timeline li.missing{color:#999}\
+    .timeline li.flaky{color:#b60}\
+    .diff{border-collapse:collapse;width:100%;table-layout:fixed}\
+    .diff td,.diff th{border:1px solid #ddd;padding:0 .5em;vertical-align:top}\
+    .diff td{font-family:monospace;white-space:pre-wrap}\
+    .diff td.removed{background:#fdd}.diff td.added{background:#dfd}";
+
+fn diff_cell(line: Option<diff::Line>) -> String {
+    match line {
+        None => "<td></td>".to_string(),
+        Some(diff::Line::Same(l)) => format!("<td>{}</td>", xml_escape(l)),
+        Some(diff::Line::Removed(l)) => format!("<td class=\"removed\">{}</td>", xml_escape(l)),
+        Some(diff::Line::Added(l)) => format!("<td class=\"added\">{}</td>", xml_escape(l)),
+    }
+}
+
+/// Unchanged lines shown around each change in JUnit failures.
+const JUNIT_DIFF_CONTEXT: usize = 3;
+
+/// Escapes text for XML content and attributes. Characters XML 1.0 doesn't allow
+/// (like the escape in ANSI colour codes) are replaced.
+fn xml_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            '\n' => escaped.push_str("&#10;"),
+            '\t' | '\r' => escaped.push(c),
+            c if c < ' ' => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Layer;
+
+    fn result(height: usize, command: &str, output: &str) -> LayerResult {
+        let layer = Layer {
+            height,
+            image_name: format!("sha256:{}", height),
+            creation_command: command.into(),
+        };
+        LayerResult::new(layer, output.into(), String::new(), output.into(), Some(0))
+    }
+
+    /// Layer 0 has no id, layers 2 and 3 both change the output.
+    fn two_changes() -> Report {
+        let commands = [
+            "ADD file in /",
+            "CMD [\"sh\"]",
+            "RUN apk add curl<8",
+            "RUN apk upgrade",
+            "RUN rm -rf /var/cache/apk",
+        ];
+        Report {
+            image: "app:latest".into(),
+            command_line: vec!["curl".into(), "--version".into()],
+            transitions: vec![
+                Transition {
+                    before: Some(result(1, "CMD [\"sh\"]", "curl 7.61")),
+                    after: result(2, "RUN apk add curl<8", "curl 7.64"),
+                },
+                Transition {
+                    before: Some(result(2, "RUN apk add curl<8", "curl 7.64")),
+                    after: result(3, "RUN apk upgrade", "curl 8.0"),
+                },
+            ],
+            missing_layers: vec![],
+            flaky: vec![Layer {
+                height: 4,
+                image_name: "sha256:4".into(),
+                creation_command: "RUN rm -rf /var/cache/apk".into(),
+            }],
+            limits: Limits {
+                memory: Some(1 << 30),
+                ..Limits::default()
+            },
+            history: commands
+                .iter()
+                .enumerate()
+                .map(|(height, command)| HistoryEntry {
+                    height,
+                    id: if height == 0 {
+                        None
+                    } else {
+                        Some(format!("sha256:{}", height))
+                    },
+                    creation_command: command.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn junit_has_a_case_for_every_runnable_layer() {
+        let xml = two_changes().to_junit();
+        assert!(xml.contains("tests=\"4\" failures=\"2\" skipped=\"1\""));
+        assert!(!xml.contains("ADD file in /"));
+        assert!(xml.contains("name=\"1: CMD [&quot;sh&quot;]\"/>"));
+        assert!(xml.contains(
+            "name=\"4: RUN rm -rf /var/cache/apk\">\n      <skipped message=\"repeated runs disagreed\"/>"
+        ));
+        assert!(xml.contains("message=\"RUN apk add curl&lt;8 changed the output\""));
+        assert!(xml.contains("&#10;-curl 7.64&#10;+curl 8.0&#10;"));
+        assert!(xml.contains("<property name=\"limits\" value=\"--memory=1073741824\"/>"));
+    }
+
+    #[test]
+    fn html_highlights_transitions() {
+        let html = two_changes().to_html();
+        assert!(html.contains(
+            "<li class=\"missing\" title=\"no image id, skipped\"><code>ADD file in /</code>"
+        ));
+        assert!(html.contains("<li><code>CMD [&quot;sh&quot;]</code></li>"));
+        assert!(html.contains("<a href=\"#layer-3\"><code>RUN apk upgrade</code></a>"));
+        assert!(html.contains("<li class=\"flaky\" title=\"runs disagreed, skipped\">"));
+        assert!(html.contains(
+            "<tr><td class=\"removed\">curl 7.64</td><td class=\"added\">curl 8.0</td></tr>"
+        ));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_round_trip() {
+        let report = Report {
+            image: "app:latest".into(),
+            command_line: vec!["curl".into(), "--version".into()],
+            transitions: vec![Transition {
+                before: None,
+                after: result(4, "/bin/sh -c apk add curl", "curl 7.61"),
+            }],
+            missing_layers: vec![MissingLayer {
+                height: 0,
+                creation_command: "ADD file:123 in /".into(),
+            }],
+            history: vec![],
+            flaky: vec![],
+            limits: Limits::default(),
+        };
+
+        let json = report.to_json();
+        assert!(json.contains("\"exit_code\": 0"));
+        let back: Report = serde_json::from_str(&json).unwrap();
+        assert_eq!(report.transitions, back.transitions);
+        assert_eq!(report.missing_layers, back.missing_layers);
+        assert_eq!(report.limits, back.limits);
+    }
+}
@@ -0,0 +1,714 @@
+//! Runs the command without a docker daemon: the layers are unpacked from an image
+//! archive into a rootfs directory, which is then run with an OCI runtime (runc, crun)
+//! or chrooted into inside a new user namespace.
+//!
+//! Layers are applied bottom-up the way overlayfs would: `.wh.<name>` files delete
+//! `<name>` from the layers below, and a `.wh..wh..opq` file empties its directory first.
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{prelude::*, Error, ErrorKind};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use flate2::read::GzDecoder;
+use indicatif::ProgressBar;
+use serde_json::{json, Value};
+
+use super::{
+    cache, mounts, ActionError, BisectError, ContainerAction, ImageArchive, Layer, LayerResult,
+    Limits, Network, RunSettings, Runtime,
+};
+
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// Runs the command on a rootfs unpacked from `archive`, with a runtime other than docker.
+#[derive(Clone)]
+pub(crate) struct RootfsContainer {
+    pub(crate) pb: Arc<ProgressBar>,
+    pub(crate) settings: RunSettings,
+    pub(crate) archive: Arc<ImageArchive>,
+    pub(crate) runtime: Runtime,
+}
+
+impl RootfsContainer {
+    fn run(&self, layer: &Layer, bundle: &Path) -> Result<LayerResult, Error> {
+        let rootfs = bundle.join("rootfs");
+        fs::create_dir_all(&rootfs)?;
+        for path in self.archive.layer_files(layer) {
+            apply_layer(&path, &rootfs)?;
+        }
+        if !self.settings.copies.is_empty() {
+            let copies = mounts::write_copies(&self.settings.copies, Vec::new())?;
+            tar::Archive::new(&copies[..]).unpack(&rootfs)?;
+        }
+
+        let config = self.archive.config(layer);
+        let mut args: Vec<String> = match &self.settings.entrypoint {
+            Some(entrypoint) if entrypoint.is_empty() => Vec::new(),
+            Some(entrypoint) => vec![entrypoint.clone()],
+            None => strings(&config["config"]["Entrypoint"]),
+        };
+        args.extend(self.settings.command_line.iter().cloned());
+        let mut env_vars = strings(&config["config"]["Env"]);
+        for var in &self.settings.env {
+            let name = var.split('=').next();
+            env_vars.retain(|v| v.split('=').next() != name);
+            env_vars.push(var.clone());
+        }
+        if !env_vars.iter().any(|v| v.starts_with("PATH=")) {
+            env_vars
+                .push("PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin".into());
+        }
+        let cwd = match self
+            .settings
+            .workdir
+            .as_deref()
+            .or_else(|| config["config"]["WorkingDir"].as_str())
+        {
+            Some(dir) if !dir.is_empty() => dir.to_string(),
+            _ => "/".to_string(),
+        };
+        let user = match self
+            .settings
+            .user
+            .as_deref()
+            .or_else(|| config["config"]["User"].as_str())
+        {
+            Some(user) if !user.is_empty() => Some(lookup_user(&rootfs, user)?),
+            _ => None,
+        };
+
+        let output = match &self.runtime {
+            Runtime::Oci(program) => {
+                let state = bundle.join("state");
+                let id = format!("docker-bisect-{}", bundle_name(layer));
+                let runtime = |args: &[&OsStr]| {
+                    let mut command = Command::new(program);
+                    command.arg("--root").arg(&state).args(args);
+                    command
+                };
+                let spec = runtime(&["spec".as_ref(), "--rootless".as_ref()])
+                    .current_dir(bundle)
+                    .output()?;
+                if !spec.status.success() {
+                    return Err(Error::other(
+                        String::from_utf8_lossy(&spec.stderr).to_string(),
+                    ));
+                }
+                let mut spec: Value =
+                    serde_json::from_slice(&fs::read(bundle.join("config.json"))?)?;
+                spec["process"]["args"] = args.into();
+                spec["process"]["env"] = env_vars.into();
+                spec["process"]["cwd"] = cwd.into();
+                spec["process"]["terminal"] = false.into();
+                spec["root"]["path"] = "rootfs".into();
+                spec["root"]["readonly"] = false.into();
+                if let Some(mounts) = spec["mounts"].as_array_mut() {
+                    for mount in &self.settings.mounts {
+                        let mode = if mount.read_only { "ro" } else { "rw" };
+                        mounts.push(json!({
+                            "destination": mount.target,
+                            "type": "bind",
+                            "source": mount.source,
+                            "options": ["rbind", mode],
+                        }));
+                    }
+                }
+                apply_limits(&mut spec, &self.settings.limits)?;
+                if let Some((uid, gid)) = user {
+                    spec["process"]["user"]["uid"] = uid.into();
+                    spec["process"]["user"]["gid"] = gid.into();
+                    // The rootless spec maps the current user to root, map it to the
+                    // user instead.
+                    if let Some(mapping) = spec.pointer_mut("/linux/uidMappings/0") {
+                        mapping["containerID"] = uid.into();
+                    }
+                    if let Some(mapping) = spec.pointer_mut("/linux/gidMappings/0") {
+                        mapping["containerID"] = gid.into();
+                    }
+                }
+                fs::write(bundle.join("config.json"), serde_json::to_vec(&spec)?)?;
+
+                let mut run = runtime(&[
+                    "run".as_ref(),
+                    "--bundle".as_ref(),
+                    bundle.as_os_str(),
+                    id.as_ref(),
+                ]);
+                let output = run_with_timeout(
+                    &mut run,
+                    self.settings.timeout,
+                    self.settings.kill_grace,
+                    &self.settings.kill_signal,
+                    |child, signal| {
+                        let _ = runtime(&["kill".as_ref(), id.as_ref(), signal.as_ref()]).output();
+                        if signal == "KILL" {
+                            let _ = child.kill();
+                        }
+                    },
+                );
+                let _ = runtime(&["delete".as_ref(), "--force".as_ref(), id.as_ref()]).output();
+                output?
+            }
+            Runtime::Chroot if !self.settings.mounts.is_empty() => {
+                return Err(Error::other(
+                    "the chroot runtime can't bind-mount, copy files in instead",
+                ))
+            }
+            Runtime::Chroot
+                if self.settings.limits.network == Network::Bridge
+                    || self.settings.limits.has_resources() =>
+            {
+                return Err(Error::other(
+                    "the chroot runtime has no bridge network or resource limits",
+                ))
+            }
+            Runtime::Chroot => {
+                let mut unshare = Command::new("unshare");
+                unshare.arg("--user");
+                match user {
+                    Some((uid, gid)) => unshare
+                        .arg(format!("--map-user={}", uid))
+                        .arg(format!("--map-group={}", gid)),
+                    None => unshare.arg("--map-root-user"),
+                };
+                if self.settings.limits.network == Network::None {
+                    unshare.arg("--net");
+                }
+                unshare
+                    .args(["--mount", "--pid", "--fork", "--kill-child"])
+                    .arg(format!("--root={}", rootfs.display()))
+                    .arg(format!("--wd={}", cwd))
+                    .arg("--")
+                    .args(&args)
+                    .env_clear()
+                    .envs(env_vars.iter().filter_map(|v| {
+                        let mut parts = v.splitn(2, '=');
+                        Some((parts.next()?, parts.next()?))
+                    }));
+                // unshare passes the signal on by dying, which --kill-child turns into
+                // SIGKILL for the command.
+                run_with_timeout(
+                    &mut unshare,
+                    self.settings.timeout,
+                    self.settings.kill_grace,
+                    &self.settings.kill_signal,
+                    send,
+                )?
+            }
+            Runtime::Docker => return Err(Error::other("docker is not a rootfs runtime")),
+        };
+
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).to_string();
+        Ok(LayerResult {
+            timed_out: output.timed_out,
+            ..LayerResult::new(
+                layer.clone(),
+                text(&output.stdout),
+                text(&output.stderr),
+                text(&output.combined),
+                Some(output.exit_code),
+            )
+        })
+    }
+}
+
+impl ContainerAction for RootfsContainer {
+    fn try_container(&self, layer: &Layer) -> Result<LayerResult, ActionError> {
+        // In the archive's directory, so that it goes too when interrupted.
+        let bundle = self
+            .archive
+            .scratch()
+            .join(format!("bundle-{}", bundle_name(layer)));
+        let result = self.run(layer, &bundle);
+        let _ = fs::remove_dir_all(&bundle);
+        self.pb.inc(1);
+        Ok(result.map_err(|e| BisectError::ContainerCreateFailed {
+            layer: layer.clone(),
+            message: e.to_string(),
+        })?)
+    }
+
+    fn skip(&self, count: u64) {
+        self.pb.inc(count);
+    }
+
+    fn fingerprint(&self) -> Option<String> {
+        self.settings.fingerprint(&self.runtime)
+    }
+}
+
+fn bundle_name(layer: &Layer) -> String {
+    format!(
+        "{}-{}",
+        std::process::id(),
+        cache::fingerprint(&layer.image_name)
+    )
+}
+
+/// Sets the network namespace and resource limits of an OCI runtime spec. Rootless
+/// runtimes can only limit resources where cgroups are delegated to the user.
+fn apply_limits(spec: &mut Value, limits: &Limits) -> Result<(), Error> {
+    let is_network = |namespace: &Value| namespace["type"] == "network";
+    match (limits.network, spec["linux"]["namespaces"].as_array_mut()) {
+        (Network::Bridge, _) => {
+            return Err(Error::other(
+                "OCI runtimes have no bridge network, use none or host",
+            ))
+        }
+        (Network::None, Some(namespaces)) if !namespaces.iter().any(is_network) => {
+            namespaces.push(json!({ "type": "network" }))
+        }
+        (Network::Host, Some(namespaces)) => namespaces.retain(|n| !is_network(n)),
+        _ => {}
+    }
+
+    if limits.has_resources() {
+        let resources = &mut spec["linux"]["resources"];
+        if let Some(memory) = limits.memory {
+            resources["memory"]["limit"] = memory.into();
+        }
+        if let Some((quota, period)) = limits.cpu_quota() {
+            resources["cpu"]["quota"] = quota.into();
+            resources["cpu"]["period"] = period.into();
+        }
+        if let Some(pids_limit) = limits.pids_limit {
+            resources["pids"]["limit"] = pids_limit.into();
+        }
+    }
+    Ok(())
+}
+
+/// The uid and gid for a `--user` value (`user`, `uid`, `user:group` or `uid:gid`), with
+/// names looked up in the rootfs's /etc/passwd and /etc/group. Like docker, a user
+/// without a group gets their primary group, or 0 if they aren't in /etc/passwd.
+fn lookup_user(rootfs: &Path, user: &str) -> Result<(u32, u32), Error> {
+    let mut parts = user.splitn(2, ':');
+    let name = parts.next().unwrap_or_default();
+    let id = |path: &str, name: &str, field: usize| -> Option<u32> {
+        // Symlinks are resolved on the host, so they aren't followed out of the rootfs.
+        let file = inside(rootfs, Path::new(path)).filter(|file| !is_symlink(file))?;
+        fs::read_to_string(file)
+            .ok()?
+            .lines()
+            .map(|line| line.split(':').collect::<Vec<_>>())
+            .find(|fields| fields.len() > 3 && (fields[0] == name || fields[2] == name))?
+            .get(field)?
+            .parse()
+            .ok()
+    };
+    let uid = name
+        .parse()
+        .ok()
+        .or_else(|| id("etc/passwd", name, 2))
+        .ok_or_else(|| Error::other(format!("no user {} in /etc/passwd", name)))?;
+    let gid = match parts.next() {
+        Some(group) => group
+            .parse()
+            .ok()
+            .or_else(|| id("etc/group", group, 2))
+            .ok_or_else(|| Error::other(format!("no group {} in /etc/group", group)))?,
+        None => id("etc/passwd", name, 3).unwrap_or(0),
+    };
+    Ok((uid, gid))
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// What a finished command printed, and how it ended.
+struct Output {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    combined: Vec<u8>,
+    exit_code: i32,
+    timed_out: bool,
+}
+
+/// Sends a signal (`TERM`, `SIGTERM`, `15`, ...) to the process.
+fn send(child: &mut Child, signal: &str) {
+    if signal == "KILL" {
+        let _ = child.kill();
+    } else {
+        let _ = Command::new("kill")
+            .args(["-s", signal, &child.id().to_string()])
+            .status();
+    }
+}
+
+/// Runs `command` and collects its output. Past the timeout `stop` is called with `signal`,
+/// and once the grace period is over too with `KILL`. A process ended by a signal gets
+/// 128 + the signal, as in a shell. Output is read for at most a second after the process
+/// ends, in case something it started still holds the pipes.
+fn run_with_timeout<F>(
+    command: &mut Command,
+    timeout: Duration,
+    grace: Duration,
+    signal: &str,
+    mut stop: F,
+) -> Result<Output, Error>
+where
+    F: FnMut(&mut Child, &str),
+{
+    let mut child = command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let combined = Arc::new(Mutex::new(Vec::new()));
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        let own = Arc::new(Mutex::new(Vec::new()));
+        let (own_copy, combined) = (own.clone(), combined.clone());
+        let reader = thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match pipe.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        own_copy
+                            .lock()
+                            .expect("output lock")
+                            .extend_from_slice(&buf[..n]);
+                        combined
+                            .lock()
+                            .expect("output lock")
+                            .extend_from_slice(&buf[..n]);
+                    }
+                    Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
+            }
+        });
+        (own, reader)
+    };
+    let (stdout, stdout_reader) = read(Box::new(child.stdout.take().expect("piped stdout")));
+    let (stderr, stderr_reader) = read(Box::new(child.stderr.take().expect("piped stderr")));
+
+    let deadline = Instant::now() + timeout;
+    let mut signalled: Option<Instant> = None;
+    let mut killed = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        let now = Instant::now();
+        match signalled {
+            None if now > deadline => {
+                stop(&mut child, signal);
+                signalled = Some(now);
+            }
+            Some(at) if !killed && now > at + grace => {
+                stop(&mut child, "KILL");
+                killed = true;
+            }
+            _ => {}
+        }
+        thread::sleep(Duration::from_millis(20));
+    };
+
+    let grace = Instant::now() + Duration::from_secs(1);
+    while !(stdout_reader.is_finished() && stderr_reader.is_finished()) && Instant::now() < grace {
+        thread::sleep(Duration::from_millis(20));
+    }
+    let code = status
+        .code()
+        .or_else(|| status.signal().map(|signal| 128 + signal))
+        .unwrap_or(-1);
+    let output = |buf: &Arc<Mutex<Vec<u8>>>| buf.lock().expect("output lock").clone();
+    Ok(Output {
+        stdout: output(&stdout),
+        stderr: output(&stderr),
+        combined: output(&combined),
+        exit_code: code,
+        timed_out: signalled.is_some(),
+    })
+}
+
+/// Opens a layer tarball, decompressing it if it's gzipped.
+fn open_layer(path: &Path) -> Result<Box<dyn Read>, Error> {
+    let mut magic = [0; 2];
+    let gzipped = File::open(path)?.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b];
+    let file = File::open(path)?;
+    Ok(if gzipped {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Applies one layer on top of `rootfs`. Whiteouts are applied in a first pass so that
+/// they only remove what the layers below added.
+fn apply_layer(path: &Path, rootfs: &Path) -> Result<(), Error> {
+    for entry in tar::Archive::new(open_layer(path)?).entries()? {
+        let entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        if !name.starts_with(WHITEOUT) {
+            continue;
+        }
+        // The whiteout's own path, so that its directory can't be a symlink either.
+        let dir = match inside(rootfs, &path) {
+            Some(whiteout) => whiteout.parent().unwrap_or(rootfs).to_path_buf(),
+            None => continue,
+        };
+        if name == OPAQUE {
+            if let Ok(children) = fs::read_dir(&dir) {
+                for child in children {
+                    remove(&child?.path())?;
+                }
+            }
+        } else {
+            remove(&dir.join(&name[WHITEOUT.len()..]))?;
+        }
+    }
+
+    for entry in tar::Archive::new(open_layer(path)?).entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.to_path_buf();
+        let name = path.file_name().and_then(OsStr::to_str).unwrap_or_default();
+        let target = match inside(rootfs, &path) {
+            Some(target) if !name.starts_with(WHITEOUT) => target,
+            _ => continue,
+        };
+        // A lower layer may have something else at this path.
+        if !entry.header().entry_type().is_dir() {
+            remove(&target)?;
+        }
+        entry.set_preserve_permissions(true);
+        entry.unpack_in(rootfs)?;
+    }
+    Ok(())
+}
+
+/// `path` within `rootfs`, or `None` if it would escape it: through `..`, or through a
+/// directory that a lower layer left as a symlink. Symlinks are resolved on the host, so
+/// one like `var/run -> /run` leads out of the rootfs. The last component may itself be
+/// a symlink; callers that would follow it must check.
+fn inside(rootfs: &Path, path: &Path) -> Option<PathBuf> {
+    let mut inside = rootfs.to_path_buf();
+    for component in path.components() {
+        if is_symlink(&inside) {
+            return None;
+        }
+        match component {
+            Component::Normal(part) => inside.push(part),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(inside)
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+fn remove(path: &Path) -> Result<(), Error> {
+    let removed = match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(_) => Ok(()),
+    };
+    match removed {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn layer_tar(path: &Path, files: &[(&str, &str)]) {
+        let mut tar = tar::Builder::new(File::create(path).unwrap());
+        for (name, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, name, content.as_bytes())
+                .unwrap();
+        }
+        tar.finish().unwrap();
+    }
+
+    #[test]
+    fn whiteouts_remove_lower_files() {
+        let dir = env::temp_dir().join(format!("docker-bisect-rootfs-{}", std::process::id()));
+        let rootfs = dir.join("rootfs");
+        fs::create_dir_all(&rootfs).unwrap();
+        layer_tar(
+            &dir.join("1.tar"),
+            &[
+                ("etc/keep", "1"),
+                ("etc/gone", "1"),
+                ("var/cache/a", "1"),
+                ("var/cache/b", "1"),
+            ],
+        );
+        layer_tar(
+            &dir.join("2.tar"),
+            &[
+                ("etc/.wh.gone", ""),
+                ("etc/keep", "2"),
+                ("var/cache/c", "2"),
+                ("var/cache/.wh..wh..opq", ""),
+            ],
+        );
+
+        apply_layer(&dir.join("1.tar"), &rootfs).unwrap();
+        apply_layer(&dir.join("2.tar"), &rootfs).unwrap();
+        let keep = fs::read_to_string(rootfs.join("etc/keep")).unwrap();
+        let mut cache: Vec<_> = fs::read_dir(rootfs.join("var/cache"))
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect();
+        cache.sort();
+        let gone = rootfs.join("etc/gone").exists();
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!("2", keep);
+        assert!(!gone);
+        assert_eq!(vec!["c"], cache);
+    }
+
+    #[test]
+    fn whiteouts_and_users_dont_follow_symlinks_out() {
+        let dir = env::temp_dir().join(format!("docker-bisect-escape-{}", std::process::id()));
+        let (rootfs, host) = (dir.join("rootfs"), dir.join("host"));
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        fs::create_dir_all(host.join("sub")).unwrap();
+        fs::write(host.join("foo"), "host").unwrap();
+        fs::write(host.join("passwd"), "app:x:1000:1001::/:/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink(&host, rootfs.join("x")).unwrap();
+        std::os::unix::fs::symlink(&host, rootfs.join("y")).unwrap();
+        std::os::unix::fs::symlink(host.join("passwd"), rootfs.join("etc/passwd")).unwrap();
+        layer_tar(
+            &dir.join("1.tar"),
+            &[("x/.wh.foo", ""), ("y/.wh..wh..opq", ""), ("x/bar", "1")],
+        );
+
+        let applied = apply_layer(&dir.join("1.tar"), &rootfs);
+        let host_files = (host.join("foo").exists(), host.join("sub").exists());
+        let written = host.join("bar").exists();
+        let user = lookup_user(&rootfs, "app");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert!(applied.is_ok());
+        assert!(!written);
+        assert_eq!(host_files, (true, true));
+        assert!(user.is_err());
+    }
+
+    #[test]
+    fn users_are_looked_up_in_the_rootfs() {
+        let rootfs = env::temp_dir().join(format!("docker-bisect-users-{}", std::process::id()));
+        fs::create_dir_all(rootfs.join("etc")).unwrap();
+        fs::write(
+            rootfs.join("etc/passwd"),
+            "root:x:0:0:root:/root:/bin/sh\napp:x:1000:1001::/home/app:/bin/sh\n",
+        )
+        .unwrap();
+        fs::write(rootfs.join("etc/group"), "root:x:0:\nstaff:x:50:app\n").unwrap();
+
+        let app = lookup_user(&rootfs, "app").unwrap();
+        let staff = lookup_user(&rootfs, "app:staff").unwrap();
+        let numeric = lookup_user(&rootfs, "1000").unwrap();
+        let unknown = lookup_user(&rootfs, "4242:4343").unwrap();
+        let missing = lookup_user(&rootfs, "nobody");
+        let _ = fs::remove_dir_all(&rootfs);
+
+        assert_eq!((1000, 1001), app);
+        assert_eq!((1000, 50), staff);
+        assert_eq!((1000, 1001), numeric);
+        assert_eq!((4242, 4343), unknown);
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn limits_edit_the_oci_spec() {
+        let spec = json!({
+            "linux": { "namespaces": [{ "type": "pid" }, { "type": "network" }] }
+        });
+        let namespaces = |limits: &Limits| {
+            let mut spec = spec.clone();
+            apply_limits(&mut spec, limits).unwrap();
+            spec["linux"]["namespaces"].as_array().unwrap().len()
+        };
+        let host = Limits {
+            network: Network::Host,
+            ..Limits::default()
+        };
+        let none = Limits {
+            network: Network::None,
+            ..Limits::default()
+        };
+        assert_eq!(1, namespaces(&host));
+        assert_eq!(2, namespaces(&none));
+
+        let mut limited = spec.clone();
+        let limits = Limits {
+            memory: Some(1 << 26),
+            pids_limit: Some(64),
+            ..Limits::default()
+        };
+        apply_limits(&mut limited, &limits).unwrap();
+        assert_eq!(
+            json!(1 << 26),
+            limited["linux"]["resources"]["memory"]["limit"]
+        );
+        assert_eq!(json!(64), limited["linux"]["resources"]["pids"]["limit"]);
+        assert!(limited["linux"]["resources"]["cpu"].is_null());
+
+        let bridge = Limits {
+            network: Network::Bridge,
+            ..Limits::default()
+        };
+        assert!(apply_limits(&mut spec.clone(), &bridge).is_err());
+    }
+
+    #[test]
+    fn timeout_signals_then_kills_the_command() {
+        let timeout = Duration::from_millis(200);
+        let grace = Duration::from_millis(300);
+
+        let mut sleep = Command::new("sh");
+        sleep.args(["-c", "echo started; sleep 10"]);
+        let output = run_with_timeout(&mut sleep, timeout, grace, "TERM", send).unwrap();
+        assert_eq!(b"started\n".to_vec(), output.stdout);
+        assert_eq!(output.stdout, output.combined);
+        assert_eq!(128 + 15, output.exit_code);
+        assert!(output.timed_out);
+
+        let mut stubborn = Command::new("sh");
+        stubborn.args([
+            "-c",
+            "trap 'echo ignored' TERM; while true; do sleep 0.05; done",
+        ]);
+        let output = run_with_timeout(&mut stubborn, timeout, grace, "TERM", send).unwrap();
+        assert_eq!(b"ignored\n".to_vec(), output.stdout);
+        assert_eq!(128 + 9, output.exit_code);
+
+        let mut quick = Command::new("true");
+        let output = run_with_timeout(&mut quick, timeout, grace, "TERM", send).unwrap();
+        assert!(!output.timed_out);
+    }
+}